@@ -0,0 +1,188 @@
+use serde::{Deserialize, Serialize};
+
+// Alerts look at a week's average rather than a single day, which is too noisy to be worth a
+// notification
+const WINDOW: usize = 7;
+
+// When an alert goes off. Levels given as a percentage are stored as a fraction, to match
+// expressions like positive/total.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Condition {
+    Above { level: f64, percent: bool },
+    Below { level: f64, percent: bool },
+    // Week-over-week change in percent
+    Rises { percent: f64 },
+    Falls { percent: f64 },
+}
+
+impl Condition {
+    // "> 10%", "below 500", "rises 20%" or "falls 20% week-over-week", already split into words
+    pub fn parse(words: &[&str]) -> Result<Condition, String> {
+        let usage = "Conditions look like > 10%, < 500, rises 20% or falls 20%";
+        let (operator, value) = match words {
+            [operator, value] => (operator.to_lowercase(), *value),
+            [operator, value, period] if is_week_over_week(period) => (operator.to_lowercase(), *value),
+            _ => return Err(usage.to_string()),
+        };
+        let (number, percent) = match value.strip_suffix("%") {
+            Some(number) => (number, true),
+            None => (value, false),
+        };
+        let number = match number.replace(",", "").parse::<f64>() {
+            Ok(number) if number.is_finite() && number >= 0.0 => number,
+            _ => return Err(format!("{value} isn't a number. {usage}", value=value, usage=usage)),
+        };
+        let level = if percent { number / 100.0 } else { number };
+        match operator.as_str() {
+            ">" | ">=" | "above" | "over" => return Ok(Condition::Above{level: level, percent: percent}),
+            "<" | "<=" | "below" | "under" => return Ok(Condition::Below{level: level, percent: percent}),
+            "rises" | "up" => return Ok(Condition::Rises{percent: number}),
+            "falls" | "drops" | "down" => return Ok(Condition::Falls{percent: number}),
+            _ => return Err(format!("Unknown condition {operator}. {usage}", operator=operator, usage=usage)),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Condition::Above{level, percent} => format!("> {level}", level=format_value(*level, *percent)),
+            Condition::Below{level, percent} => format!("< {level}", level=format_value(*level, *percent)),
+            Condition::Rises{percent} => format!("rises {percent}% week-over-week", percent=percent),
+            Condition::Falls{percent} => format!("falls {percent}% week-over-week", percent=percent),
+        }
+    }
+
+    // Whether the condition holds for a metric's daily values, oldest first. Says why when it
+    // does.
+    pub fn check(&self, values: &[f32]) -> Option<String> {
+        match self {
+            Condition::Above{level, percent} | Condition::Below{level, percent} => {
+                if values.len() < WINDOW {
+                    return None;
+                }
+                let current = average(&values[values.len()-WINDOW..]);
+                let holds = match self {
+                    Condition::Above{..} => current > *level,
+                    _ => current < *level,
+                };
+                if !holds {
+                    return None;
+                }
+                return Some(format!("the 7-day average is {value}", value=format_value(current, *percent)));
+            },
+            Condition::Rises{percent} | Condition::Falls{percent} => {
+                if values.len() < WINDOW * 2 {
+                    return None;
+                }
+                let current = average(&values[values.len()-WINDOW..]);
+                let previous = average(&values[values.len()-WINDOW*2..values.len()-WINDOW]);
+                // Any change from nothing is infinite, which isn't useful to hear about
+                if previous <= 0.0 {
+                    return None;
+                }
+                let change = (current / previous - 1.0) * 100.0;
+                let holds = match self {
+                    Condition::Rises{..} => change > *percent,
+                    _ => -change > *percent,
+                };
+                if !holds {
+                    return None;
+                }
+                let direction = if change > 0.0 { "up" } else { "down" };
+                return Some(format!("the 7-day average is {current}, {direction} {change:.0}% from {previous} the week before",
+                                    current=format_value(current, false), direction=direction, change=change.abs(), previous=format_value(previous, false)));
+            },
+        }
+    }
+}
+
+fn is_week_over_week(word: &str) -> bool {
+    return ["week-over-week", "wow", "w/w", "weekly"].contains(&word.to_lowercase().as_str());
+}
+
+fn average(values: &[f32]) -> f64 {
+    return values.iter().map(|v| *v as f64).sum::<f64>() / values.len() as f64;
+}
+
+fn format_value(value: f64, percent: bool) -> String {
+    if percent {
+        return format!("{value}%", value=round(value * 100.0));
+    }
+    return round(value);
+}
+
+// Whole numbers for counts, a couple of decimal places for rates
+fn round(value: f64) -> String {
+    if value.abs() >= 100.0 {
+        return format!("{:.0}", value);
+    }
+    let rounded = format!("{:.2}", value);
+    return rounded.trim_end_matches('0').trim_end_matches('.').to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Condition, String> {
+        return Condition::parse(&text.split_whitespace().collect::<Vec<&str>>());
+    }
+
+    // A week of one value followed by a week of another
+    fn weeks(previous: f32, current: f32) -> Vec<f32> {
+        let mut values = vec![previous; WINDOW];
+        values.extend(vec![current; WINDOW]);
+        return values;
+    }
+
+    #[test]
+    fn parses_conditions() {
+        assert_eq!(parse("> 10%"), Ok(Condition::Above{level: 0.1, percent: true}));
+        assert_eq!(parse("above 1,500"), Ok(Condition::Above{level: 1500.0, percent: false}));
+        assert_eq!(parse("< 500"), Ok(Condition::Below{level: 500.0, percent: false}));
+        assert_eq!(parse("Rises 20%"), Ok(Condition::Rises{percent: 20.0}));
+        assert_eq!(parse("falls 20% week-over-week"), Ok(Condition::Falls{percent: 20.0}));
+        assert_eq!(parse("drops 5 WoW"), Ok(Condition::Falls{percent: 5.0}));
+    }
+
+    #[test]
+    fn rejects_bad_conditions() {
+        for text in ["", ">", "> 10 20", "> lots", "> -5", "> inf", "> NaN", "equals 5", "rises 20% forever"].iter() {
+            assert!(parse(text).is_err(), "{:}", text);
+        }
+    }
+
+    #[test]
+    fn describes_conditions() {
+        assert_eq!(Condition::Above{level: 0.1, percent: true}.describe(), "> 10%");
+        assert_eq!(Condition::Below{level: 500.0, percent: false}.describe(), "< 500");
+        assert_eq!(Condition::Rises{percent: 20.0}.describe(), "rises 20% week-over-week");
+    }
+
+    #[test]
+    fn levels_use_the_weekly_average() {
+        let above = Condition::Above{level: 100.0, percent: false};
+        assert_eq!(above.check(&weeks(0.0, 150.0)), Some("the 7-day average is 150".to_string()));
+        assert_eq!(above.check(&weeks(150.0, 50.0)), None);
+        // One big day isn't enough
+        assert_eq!(above.check(&[0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 700.0]), None);
+        // Nor is less than a week of data
+        assert_eq!(above.check(&[500.0; WINDOW - 1]), None);
+        let below = Condition::Below{level: 0.05, percent: true};
+        assert_eq!(below.check(&weeks(0.1, 0.04)), Some("the 7-day average is 4%".to_string()));
+    }
+
+    #[test]
+    fn changes_compare_weeks() {
+        let rises = Condition::Rises{percent: 20.0};
+        assert_eq!(rises.check(&weeks(100.0, 150.0)), Some("the 7-day average is 150, up 50% from 100 the week before".to_string()));
+        assert_eq!(rises.check(&weeks(100.0, 110.0)), None);
+        assert_eq!(rises.check(&weeks(150.0, 100.0)), None);
+        // Any rise from nothing is infinite
+        assert_eq!(rises.check(&weeks(0.0, 100.0)), None);
+        assert_eq!(rises.check(&[100.0; WINDOW * 2 - 1]), None);
+        let falls = Condition::Falls{percent: 20.0};
+        assert_eq!(falls.check(&weeks(100.0, 50.0)), Some("the 7-day average is 50, down 50% from 100 the week before".to_string()));
+        assert_eq!(falls.check(&weeks(100.0, 150.0)), None);
+    }
+}
@@ -0,0 +1,90 @@
+use serde_json::Value;
+
+// Slack caps the number of fields in a section
+const MAX_FIELDS: usize = 10;
+
+// A Block Kit message along with the plain text Slack shows in notifications and clients that
+// can't display blocks. See https://api.slack.com/block-kit
+#[derive(Debug, Clone)]
+pub struct Message {
+    text: String,
+    blocks: Vec<Value>,
+}
+
+impl Message {
+    // A message with just the fallback text, which is also what's shown if no blocks are added
+    pub fn new(text: &str) -> Message {
+        return Message{text: text.to_string(), blocks: Vec::new()};
+    }
+
+    pub fn text(&self) -> &str {
+        return &self.text;
+    }
+
+    pub fn blocks(&self) -> &Vec<Value> {
+        return &self.blocks;
+    }
+
+    pub fn push_text(mut self, text: &str) -> Message {
+        if !self.text.is_empty() {
+            self.text.push_str("\n");
+        }
+        self.text.push_str(text);
+        return self;
+    }
+
+    pub fn header(mut self, text: &str) -> Message {
+        self.blocks.push(serde_json::json!({
+            "type": "header",
+            "text": {"type": "plain_text", "text": text},
+        }));
+        return self;
+    }
+
+    pub fn section(mut self, markdown: &str) -> Message {
+        self.blocks.push(serde_json::json!({
+            "type": "section",
+            "text": {"type": "mrkdwn", "text": markdown},
+        }));
+        return self;
+    }
+
+    // Label/value pairs laid out as a two column table
+    pub fn fields(mut self, fields: &[(&str, String)]) -> Message {
+        for chunk in fields.chunks(MAX_FIELDS) {
+            let fields: Vec<Value> = chunk.iter()
+                .map(|(label, value)| serde_json::json!({
+                    "type": "mrkdwn",
+                    "text": format!("*{label}*\n{value}", label=label, value=value),
+                }))
+                .collect();
+            self.blocks.push(serde_json::json!({"type": "section", "fields": fields}));
+        }
+        return self;
+    }
+
+    pub fn image(mut self, url: &str, title: &str, alt_text: &str) -> Message {
+        self.blocks.push(serde_json::json!({
+            "type": "image",
+            "image_url": url,
+            "title": {"type": "plain_text", "text": title},
+            "alt_text": alt_text,
+        }));
+        return self;
+    }
+
+    // Any other block, e.g. interactive elements
+    pub fn block(mut self, block: Value) -> Message {
+        self.blocks.push(block);
+        return self;
+    }
+
+    // Small print under everything else, e.g. where the data came from
+    pub fn context(mut self, markdown: &str) -> Message {
+        self.blocks.push(serde_json::json!({
+            "type": "context",
+            "elements": [{"type": "mrkdwn", "text": markdown}],
+        }));
+        return self;
+    }
+}
@@ -0,0 +1,152 @@
+use crate::config::BriefingConfig;
+use crate::coronabot::Coronabot;
+use crate::dispatch::Dispatcher;
+use crate::schedule::{self, Schedule};
+use crate::store::Subscription;
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// How often to check whether a briefing is due
+const TICK: Duration = Duration::from_secs(30);
+
+// A configured briefing, or a subscription someone asked for
+struct Briefing {
+    // Identifies it in the store: the subscription id, or the channel, schedule and commands of a
+    // configured one
+    key: String,
+    channel: String,
+    schedule: Schedule,
+    timezone: Tz,
+    commands: Vec<String>,
+    // Configured briefings start with a header, subscriptions are just the report
+    header: bool,
+    // It goes out once this has passed and there's data the last one didn't cover
+    due: Option<DateTime<Tz>>,
+    // Kept in the store as well, so it survives restarts
+    last_data_date: Option<NaiveDate>,
+}
+
+impl Briefing {
+    fn from_config(bot: &Coronabot, config: &BriefingConfig) -> Result<Briefing, String> {
+        let key = format!("{channel}|{schedule}|{commands}", channel=config.channel, schedule=config.schedule, commands=config.commands.join("|"));
+        return Briefing::new(bot, key, &config.channel, &config.schedule, &config.timezone, config.commands.clone(), true);
+    }
+
+    fn from_subscription(bot: &Coronabot, channel: &str, subscription: &Subscription) -> Result<Briefing, String> {
+        return Briefing::new(bot, subscription.id.clone(), channel, &subscription.schedule, &subscription.timezone, vec![subscription.query.clone()], false);
+    }
+
+    fn new(bot: &Coronabot, key: String, channel: &str, schedule: &str, timezone: &str, commands: Vec<String>, header: bool) -> Result<Briefing, String> {
+        let expression = schedule;
+        let schedule = Schedule::parse(expression)?;
+        let timezone = schedule::parse_timezone(timezone)?;
+        let due = schedule.next_after(&Utc::now().with_timezone(&timezone));
+        if due.is_none() {
+            return Err(format!("Schedule {:?} never happens", expression));
+        }
+        let last_data_date = bot.briefed(&key);
+        return Ok(Briefing{
+            key: key,
+            channel: channel.to_string(),
+            schedule: schedule,
+            timezone: timezone,
+            commands: commands,
+            header: header,
+            due: due,
+            last_data_date: last_data_date,
+        });
+    }
+
+    fn tick(&mut self, bot: &Coronabot, dispatcher: &Dispatcher, now: DateTime<Utc>, latest_date: Option<NaiveDate>) {
+        let due = match self.due {
+            Some(due) => due,
+            None => return,
+        };
+        let now = now.with_timezone(&self.timezone);
+        if now < due {
+            return;
+        }
+        if latest_date.is_some() && latest_date > self.last_data_date {
+            match self.send(dispatcher, latest_date.unwrap()) {
+                Ok(()) => {},
+                Err(err) => {
+                    println!("Couldn't post the briefing to {:}, trying again shortly: {:}", self.channel, err);
+                    return;
+                }
+            }
+            self.last_data_date = latest_date;
+            bot.set_briefed(&self.key, latest_date.unwrap());
+            self.due = self.schedule.next_after(&now);
+            return;
+        }
+        // Still waiting for the day's data, give up once the next one is due
+        match self.schedule.next_after(&due) {
+            Some(next) if now >= next => {
+                println!("No new data for the briefing in {:} due at {:}, skipping it", self.channel, due);
+                self.due = self.schedule.next_after(&now);
+            },
+            _ => {}
+        }
+    }
+
+    fn send(&self, dispatcher: &Dispatcher, data_date: NaiveDate) -> Result<(), String> {
+        println!("Posting briefing to {:}", self.channel);
+        let title = match self.header {
+            true => Some(format!("Daily briefing with data for {date}", date=data_date)),
+            false => None,
+        };
+        return dispatcher.dispatch_briefing(&self.channel, title, self.commands.clone());
+    }
+}
+
+// Picks up subscriptions that have been added since the last tick and drops removed ones, keyed by
+// subscription id
+fn sync_subscriptions(bot: &Coronabot, subscriptions: &mut HashMap<String, Briefing>) {
+    let current = bot.subscriptions();
+    let ids: HashSet<&str> = current.iter().map(|(_, subscription)| subscription.id.as_str()).collect();
+    subscriptions.retain(|id, _| ids.contains(id.as_str()));
+    for (channel, subscription) in current.iter() {
+        if subscriptions.contains_key(&subscription.id) {
+            continue;
+        }
+        match Briefing::from_subscription(bot, channel, subscription) {
+            Ok(briefing) => {
+                println!("Subscription to {:} in {:} next due at {:}", subscription.query, channel, briefing.due.unwrap());
+                subscriptions.insert(subscription.id.clone(), briefing);
+            },
+            Err(err) => println!("Bad subscription to {:} in {:}: {:}", subscription.query, channel, err),
+        }
+    }
+}
+
+// Posts the configured briefings and everyone's subscriptions from a background thread, running
+// them on the dispatcher's worker pool. Panics on a bad briefing schedule or timezone, like a config
+// that doesn't parse.
+pub fn start(bot: Arc<Coronabot>, dispatcher: Arc<Dispatcher>, configs: &Vec<BriefingConfig>) {
+    let mut briefings = Vec::new();
+    for config in configs.iter() {
+        match Briefing::from_config(&bot, config) {
+            Ok(briefing) => {
+                println!("Briefing {:} next due at {:}", briefing.channel, briefing.due.unwrap());
+                briefings.push(briefing);
+            },
+            Err(err) => panic!("Bad briefing for {:}: {:}", config.channel, err),
+        }
+    }
+    let mut subscriptions = HashMap::new();
+    thread::spawn(move || {
+        loop {
+            sync_subscriptions(&bot, &mut subscriptions);
+            let now = Utc::now();
+            let latest_date = bot.latest_date();
+            for briefing in briefings.iter_mut().chain(subscriptions.values_mut()) {
+                briefing.tick(&bot, &dispatcher, now, latest_date);
+            }
+            thread::sleep(TICK);
+        }
+    });
+}
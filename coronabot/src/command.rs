@@ -0,0 +1,731 @@
+use crate::alerts::Condition;
+use crate::chart::{self, ChartSpec, Theme};
+use crate::daterange::{self, DateRange};
+use crate::expressions;
+use crate::regions;
+use crate::schedule;
+
+// Everything the bot can be asked to do, parsed from the text after the mention
+#[derive(Debug, Clone)]
+pub enum Command {
+    // Overview of every command, or details of the one named
+    Help { topic: Option<String> },
+    Define { name: String, expression: String },
+    Undefine { name: String },
+    List,
+    // None asks which theme the channel uses
+    Theme { theme: Option<Theme> },
+    Top,
+    // U.S. new cases, or a saved expression/variable when metric is given
+    Latest { metric: Option<String>, range: DateRange, spec: ChartSpec },
+    // Same as Latest for a state, combination of states or group, optionally against another
+    State { region: String, compare: Option<String>, metric: Option<String>, range: DateRange, spec: ChartSpec },
+    Custom { region: String, expression: String, range: DateRange, spec: ChartSpec },
+    // query is the report to send, schedule a cron-style schedule in timezone (or the configured
+    // one when None)
+    Subscribe { query: String, schedule: String, timezone: Option<String>, description: String },
+    // A subscription's number in the list, its report or "all"
+    Unsubscribe { which: String },
+    Subscriptions,
+    Alert { region: String, metric: String, condition: Condition },
+    // An alert's number in the list or "all"
+    Unalert { which: String },
+    Alerts,
+}
+
+// What a command's parser gets to work with
+struct Args<'a> {
+    // The text after the command name, with any date range and chart options removed
+    rest: &'a str,
+    range: DateRange,
+    spec: ChartSpec,
+}
+
+impl<'a> Args<'a> {
+    fn words(&self) -> Vec<&'a str> {
+        return self.rest.split_whitespace().collect();
+    }
+}
+
+struct CommandDef {
+    name: &'static str,
+    // Whether the command starts with its name. There's one that doesn't: anything that isn't
+    // another command is taken to be a state.
+    keyword: bool,
+    // Chart commands take a date range and chart options anywhere in their arguments
+    chart: bool,
+    takes_region: bool,
+    takes_expression: bool,
+    parse: fn(Args) -> Result<Command, String>,
+
+    // For help
+    usage: &'static str,
+    summary: &'static str,
+    details: &'static str,
+    examples: &'static [&'static str],
+}
+
+// In the order they're listed in help
+const COMMANDS: &[CommandDef] = &[
+    CommandDef{
+        name: "latest", keyword: true, chart: true, takes_region: false, takes_expression: false, parse: parse_latest,
+        usage: "latest [metric]",
+        summary: "U.S. new positive cases",
+        details: "Charts daily new cases and % positive for the whole country. Given a metric (a variable or saved expression) charts that instead.",
+        examples: &["latest", "latest last 30d", "latest positive scale=log"],
+    },
+    CommandDef{
+        name: "state", keyword: false, chart: true, takes_region: true, takes_expression: false, parse: parse_region,
+        usage: "<state> [metric] [vs <state>]",
+        summary: "new positive cases in a state",
+        details: "Same as latest, for a single state or several combined. States can be given as a postal code, name or FIPS code. With vs, the second state is drawn against the right-hand axis.",
+        examples: &["CA", "new york last 2w", "CA+OR+WA positive", "west y2=on", "CA vs NY last 30d"],
+    },
+    CommandDef{
+        name: "custom", keyword: true, chart: true, takes_region: true, takes_expression: true, parse: parse_custom,
+        usage: "custom <state> y1 <expression>",
+        summary: "chart any expression of a state's daily numbers (beta)",
+        details: "The expression is evaluated for each day's new numbers.",
+        examples: &["custom CA y1 positive/total", "custom NY y1 log(dead) since 2020-04-01"],
+    },
+    CommandDef{
+        name: "top", keyword: true, chart: false, takes_region: false, takes_expression: false, parse: parse_top,
+        usage: "top",
+        summary: "the day's worst states",
+        details: "Lists the states with the highest mortality rate and the fastest growth in positive tests and in deaths, in percent since the day before, in the latest data.",
+        examples: &["top"],
+    },
+    CommandDef{
+        name: "define", keyword: true, chart: false, takes_region: false, takes_expression: true, parse: parse_define,
+        usage: "define <name> = <expression>",
+        summary: "save an expression for this channel",
+        details: "Saved expressions can be used as a metric or inside other expressions, in this channel only.",
+        examples: &["define rate = positive/total", "CA rate"],
+    },
+    CommandDef{
+        name: "undefine", keyword: true, chart: false, takes_region: false, takes_expression: false, parse: parse_undefine,
+        usage: "undefine <name>",
+        summary: "remove a saved expression",
+        details: "Removes an expression saved with define.",
+        examples: &["undefine rate"],
+    },
+    CommandDef{
+        name: "list", keyword: true, chart: false, takes_region: false, takes_expression: false, parse: parse_list,
+        usage: "list",
+        summary: "show this channel's saved expressions",
+        details: "Lists the expressions saved with define in this channel.",
+        examples: &["list"],
+    },
+    CommandDef{
+        name: "theme", keyword: true, chart: false, takes_region: false, takes_expression: false, parse: parse_theme,
+        usage: "theme [light|dark]",
+        summary: "set this channel's default chart theme",
+        details: "Charts in this channel use the theme unless a command asks for another with theme=. Without a theme, says which one the channel uses.",
+        examples: &["theme dark", "theme"],
+    },
+    CommandDef{
+        name: "subscribe", keyword: true, chart: false, takes_region: false, takes_expression: false, parse: parse_subscribe,
+        usage: "subscribe <report> [daily|weekdays|<day>] [at] <time> [timezone]",
+        summary: "get a report here on a schedule",
+        details: "Sends the report (latest, top, a state or custom, with any range or options) every day, on weekdays, on weekends or on one day of the week, once there's new data. \
+        Subscribing in a DM sends the reports just to you. Times are in the bot's timezone unless one is given, like America/Chicago.",
+        examples: &["subscribe CA daily 9am", "subscribe latest weekdays at 8:30am America/New_York", "subscribe top monday 17:00"],
+    },
+    CommandDef{
+        name: "unsubscribe", keyword: true, chart: false, takes_region: false, takes_expression: false, parse: parse_unsubscribe,
+        usage: "unsubscribe <number|report|all>",
+        summary: "stop a subscription",
+        details: "Stops a subscription by its number in subscriptions, its report or all of this channel's.",
+        examples: &["unsubscribe 1", "unsubscribe CA", "unsubscribe all"],
+    },
+    CommandDef{
+        name: "subscriptions", keyword: true, chart: false, takes_region: false, takes_expression: false, parse: parse_subscriptions,
+        usage: "subscriptions",
+        summary: "show this channel's subscriptions",
+        details: "Lists the reports this channel (or DM) is subscribed to and when they're sent.",
+        examples: &["subscriptions"],
+    },
+    CommandDef{
+        name: "alert", keyword: true, chart: false, takes_region: true, takes_expression: false, parse: parse_alert,
+        usage: "alert <state> <metric> <condition>",
+        summary: "post here when a metric crosses a level",
+        details: "Checks the state's 7-day average of the metric (a variable or saved expression) whenever new data comes in, and posts here when the condition starts to hold. \
+        Conditions are > or < a level, which can be a percentage, or rises or falls by a percentage week-over-week.",
+        examples: &["alert CA positive rises 20%", "define positivity = positive/total", "alert CA positivity > 10%", "alert new york dead < 5"],
+    },
+    CommandDef{
+        name: "unalert", keyword: true, chart: false, takes_region: false, takes_expression: false, parse: parse_unalert,
+        usage: "unalert <number|all>",
+        summary: "remove an alert",
+        details: "Removes an alert by its number in alerts, or all of this channel's.",
+        examples: &["unalert 1", "unalert all"],
+    },
+    CommandDef{
+        name: "alerts", keyword: true, chart: false, takes_region: false, takes_expression: false, parse: parse_alerts,
+        usage: "alerts",
+        summary: "show this channel's alerts",
+        details: "Lists the alerts set up in this channel and whether they're currently met.",
+        examples: &["alerts"],
+    },
+    CommandDef{
+        name: "help", keyword: true, chart: false, takes_region: false, takes_expression: false, parse: parse_help,
+        usage: "help [command]",
+        summary: "this message, or details and examples for one command",
+        details: "Lists every command, or explains the one given.",
+        examples: &["help", "help custom"],
+    },
+];
+
+// Everything after the first n whitespace-separated words of text, untouched
+pub fn rest_of(text: &str, n: usize) -> &str {
+    let mut rest = text.trim_start();
+    for _ in 0..n {
+        match rest.find(char::is_whitespace) {
+            Some(i) => rest = rest[i..].trim_start(),
+            None => return "",
+        }
+    }
+    return rest;
+}
+
+// Parses a command (without the leading mention). spec is the starting point for chart options,
+// e.g. with the channel's theme applied.
+pub fn parse(text: &str, spec: ChartSpec) -> Result<Command, String> {
+    let first = text.split_whitespace().next().unwrap_or("").to_lowercase();
+    let (def, rest) = match COMMANDS.iter().find(|def| def.keyword && def.name == first) {
+        Some(def) => (def, rest_of(text, 1)),
+        None => (COMMANDS.iter().find(|def| !def.keyword).unwrap(), text),
+    };
+    let (rest, range, spec) = if def.chart {
+        let (rest, range) = daterange::extract(rest)?;
+        let (rest, spec) = chart::extract_options(&rest, spec)?;
+        (rest, range, spec)
+    } else {
+        (rest.trim().to_string(), DateRange::All, spec)
+    };
+    let args = Args{rest: &rest, range: range, spec: spec};
+    return (def.parse)(args);
+}
+
+// The help text for every command, or just topic. groups are the configured region names.
+pub fn help(topic: Option<&str>, groups: &[&str]) -> String {
+    let def = match topic {
+        Some(topic) => COMMANDS.iter().find(|def| def.name == topic),
+        None => None,
+    };
+    let def = match def {
+        Some(def) => def,
+        None => {
+            let mut to_send = "Usage:".to_string();
+            for def in COMMANDS.iter() {
+                to_send.push_str(&format!("\n@coronabot {usage}: {summary}", usage=def.usage, summary=def.summary));
+            }
+            to_send.push_str("\nAny chart can be limited to a date range (last 30d, since 2020-06-01, from 2020-04-01 to 2020-06-01) and styled with options like scale=log or theme=dark.");
+            to_send.push_str("\nFor details and examples: @coronabot help <command>, e.g. @coronabot help custom");
+            return to_send;
+        }
+    };
+
+    let mut to_send = format!("@coronabot {usage}\n{details}", usage=def.usage, details=def.details);
+    if def.takes_region {
+        to_send.push_str(&format!("\nCombine states with + (CA+OR+WA) or use a region: {groups}", groups=groups.join(", ")));
+    }
+    if def.takes_expression {
+        to_send.push_str(&format!("\nExpressions can use these variables: {variables} and functions: {functions}. \
+        For example (positive/total) for infection rate.",
+                                  variables=expressions::VARIABLES.join(", "),
+                                  functions=expressions::FUNCTIONS.join(", ")));
+    }
+    if def.chart {
+        to_send.push_str("\nDate ranges: last <n>d (or w, m), since <YYYY-MM-DD>, from <YYYY-MM-DD> to <YYYY-MM-DD>");
+        to_send.push_str(&format!("\nChart options: {options}", options=chart::OPTIONS_HELP));
+    }
+    to_send.push_str("\nExamples:");
+    for example in def.examples.iter() {
+        to_send.push_str(&format!("\n@coronabot {example}", example=example));
+    }
+    return to_send;
+}
+
+fn parse_help(args: Args) -> Result<Command, String> {
+    match args.words().as_slice() {
+        [] => return Ok(Command::Help{topic: None}),
+        [topic] => {
+            let topic = topic.to_lowercase();
+            if !COMMANDS.iter().any(|def| def.name == topic) {
+                let names: Vec<&str> = COMMANDS.iter().map(|def| def.name).collect();
+                return Err(format!("There's no command called {topic}, try one of {names}", topic=topic, names=names.join(", ")));
+            }
+            return Ok(Command::Help{topic: Some(topic)});
+        },
+        _ => return Err("Usage: @coronabot help [command]".to_string()),
+    }
+}
+
+fn parse_define(args: Args) -> Result<Command, String> {
+    let usage = "Usage: @coronabot define <name> = <expression>";
+    let eq = match args.rest.find("=") {
+        Some(eq) => eq,
+        None => return Err(format!("Missing '='. {usage}", usage=usage)),
+    };
+    let name = args.rest[..eq].trim().to_string();
+    let expression = args.rest[eq+1..].trim().to_string();
+    if expression.is_empty() {
+        return Err(format!("Missing expression. {usage}", usage=usage));
+    }
+    return Ok(Command::Define{name: name, expression: expression});
+}
+
+fn parse_undefine(args: Args) -> Result<Command, String> {
+    match args.words().as_slice() {
+        [name] => return Ok(Command::Undefine{name: name.to_string()}),
+        _ => return Err("Usage: @coronabot undefine <name>".to_string()),
+    }
+}
+
+fn parse_list(args: Args) -> Result<Command, String> {
+    if !args.rest.is_empty() {
+        return Err("Usage: @coronabot list".to_string());
+    }
+    return Ok(Command::List);
+}
+
+fn parse_theme(args: Args) -> Result<Command, String> {
+    match args.words().as_slice() {
+        [] => return Ok(Command::Theme{theme: None}),
+        [theme] => return Ok(Command::Theme{theme: Some(Theme::parse(theme)?)}),
+        _ => return Err("Usage: @coronabot theme <light|dark>".to_string()),
+    }
+}
+
+fn parse_top(args: Args) -> Result<Command, String> {
+    if !args.rest.is_empty() {
+        return Err("Usage: @coronabot top".to_string());
+    }
+    return Ok(Command::Top);
+}
+
+// Words that say which days a subscription is sent on, with the cron day-of-week field and how to
+// describe it. Each also works with an s on the end, e.g. mondays.
+const SUBSCRIPTION_DAYS: &[(&str, &str, &str)] = &[
+    ("daily", "*", "every day"),
+    ("weekday", "1-5", "on weekdays"),
+    ("weekend", "0,6", "on weekends"),
+    ("sunday", "0", "on Sundays"),
+    ("monday", "1", "on Mondays"),
+    ("tuesday", "2", "on Tuesdays"),
+    ("wednesday", "3", "on Wednesdays"),
+    ("thursday", "4", "on Thursdays"),
+    ("friday", "5", "on Fridays"),
+    ("saturday", "6", "on Saturdays"),
+];
+
+// <report> [days] [at] <time> [timezone], read from the end since the report can be any length
+fn parse_subscribe(args: Args) -> Result<Command, String> {
+    let usage = "Usage: @coronabot subscribe <report> [daily|weekdays|<day>] [at] <time> [timezone]";
+    let mut words = args.words();
+
+    let timezone = match words.last() {
+        Some(word) if word.to_uppercase() == "UTC" => Some("UTC".to_string()),
+        Some(word) if word.contains("/") => {
+            schedule::parse_timezone(word)?;
+            Some(word.to_string())
+        },
+        _ => None,
+    };
+    if timezone.is_some() {
+        words.pop();
+    }
+
+    // The time, which can be split like "9 am"
+    let mut time = match words.pop() {
+        Some(word) => word.to_lowercase(),
+        None => return Err(format!("Missing report and time. {usage}", usage=usage)),
+    };
+    if (time == "am" || time == "pm") && !words.is_empty() {
+        time = format!("{hour}{time}", hour=words.pop().unwrap(), time=time);
+    }
+    let (hour, minute) = match parse_time(&time) {
+        Some(time) => time,
+        None => return Err(format!("{time} isn't a time, try 9am or 17:30. {usage}", time=time, usage=usage)),
+    };
+    if words.last().map(|w| w.to_lowercase()) == Some("at".to_string()) {
+        words.pop();
+    }
+
+    let days = words.last().and_then(|word| {
+        let word = word.to_lowercase();
+        SUBSCRIPTION_DAYS.iter().find(|(name, _, _)| word == *name || word == format!("{name}s", name=name))
+    });
+    let (day_of_week, days_description) = match days {
+        Some((_, day_of_week, description)) => {
+            words.pop();
+            (*day_of_week, *description)
+        },
+        None => ("*", "every day"),
+    };
+
+    if words.is_empty() {
+        return Err(format!("Missing report. {usage}", usage=usage));
+    }
+    let query = words.join(" ");
+    // Better to find out about a bad report now than when it's due
+    match parse(&query, ChartSpec::default())? {
+        Command::Latest{..} | Command::State{..} | Command::Custom{..} | Command::Top => {},
+        _ => return Err("Only reports can be subscribed to: latest, top, a state or custom".to_string()),
+    }
+
+    return Ok(Command::Subscribe{
+        query: query,
+        schedule: format!("{minute} {hour} * * {day_of_week}", minute=minute, hour=hour, day_of_week=day_of_week),
+        timezone: timezone,
+        description: format!("{days} at {hour}:{minute:02}", days=days_description, hour=hour, minute=minute),
+    });
+}
+
+// 9am, 9:30pm or 21:30 as hour and minute. A bare number isn't taken as a time, it's too easily
+// part of the report.
+fn parse_time(time: &str) -> Option<(u32, u32)> {
+    let (time, pm) = if time.ends_with("am") {
+        (&time[..time.len()-2], Some(false))
+    } else if time.ends_with("pm") {
+        (&time[..time.len()-2], Some(true))
+    } else {
+        (time, None)
+    };
+    let (hour, minute) = match time.find(":") {
+        Some(colon) => (time[..colon].parse::<u32>().ok()?, time[colon+1..].parse::<u32>().ok()?),
+        None if pm.is_some() => (time.parse::<u32>().ok()?, 0),
+        None => return None,
+    };
+    if minute > 59 {
+        return None;
+    }
+    match pm {
+        Some(pm) if hour >= 1 && hour <= 12 => return Some((hour % 12 + if pm { 12 } else { 0 }, minute)),
+        Some(_) => return None,
+        None if hour <= 23 => return Some((hour, minute)),
+        None => return None,
+    }
+}
+
+fn parse_unsubscribe(args: Args) -> Result<Command, String> {
+    if args.rest.is_empty() {
+        return Err("Usage: @coronabot unsubscribe <number|report|all>".to_string());
+    }
+    return Ok(Command::Unsubscribe{which: args.rest.to_string()});
+}
+
+fn parse_subscriptions(args: Args) -> Result<Command, String> {
+    if !args.rest.is_empty() {
+        return Err("Usage: @coronabot subscriptions".to_string());
+    }
+    return Ok(Command::Subscriptions);
+}
+
+// <region> <metric> <condition>, where the region may be more than one word and the condition
+// starts with an operator like > or rises
+fn parse_alert(args: Args) -> Result<Command, String> {
+    let usage = "Usage: @coronabot alert <state> <metric> <condition>, e.g. @coronabot alert CA positive rises 20%";
+    let words = args.words();
+    // The region and metric come first, so a condition word among them (e.g. a metric called
+    // down) is part of those rather than the condition
+    let operator = match words.iter().skip(2).position(|w| Condition::parse(&[*w, "0"]).is_ok()) {
+        Some(operator) => operator + 2,
+        None => return Err(usage.to_string()),
+    };
+    let condition = Condition::parse(&words[operator..])?;
+    return Ok(Command::Alert{
+        region: words[..operator-1].join(" "),
+        metric: words[operator-1].to_string(),
+        condition: condition,
+    });
+}
+
+fn parse_unalert(args: Args) -> Result<Command, String> {
+    if args.rest.is_empty() {
+        return Err("Usage: @coronabot unalert <number|all>".to_string());
+    }
+    return Ok(Command::Unalert{which: args.rest.to_string()});
+}
+
+fn parse_alerts(args: Args) -> Result<Command, String> {
+    if !args.rest.is_empty() {
+        return Err("Usage: @coronabot alerts".to_string());
+    }
+    return Ok(Command::Alerts);
+}
+
+fn parse_latest(args: Args) -> Result<Command, String> {
+    let metric = match args.words().as_slice() {
+        [] => None,
+        [metric] => Some(metric.to_string()),
+        _ => return Err("Usage: @coronabot latest [metric]".to_string()),
+    };
+    return Ok(Command::Latest{metric: metric, range: args.range, spec: args.spec});
+}
+
+fn parse_custom(args: Args) -> Result<Command, String> {
+    let usage = "Usage: @coronabot custom <state> y1 <expression>";
+    let words = args.words();
+    // State names can be more than one word, so the region is everything before y1
+    let y1 = match words.iter().skip(1).position(|w| w.to_lowercase() == "y1") {
+        Some(i) => i + 1,
+        None => return Err(format!("Missing y-axis specifier. {usage}", usage=usage)),
+    };
+    if y1 + 1 >= words.len() {
+        return Err(format!("Missing expression. {usage}", usage=usage));
+    }
+    return Ok(Command::Custom{
+        region: words[..y1].join(" "),
+        expression: rest_of(args.rest, y1 + 1).to_string(),
+        range: args.range,
+        spec: args.spec,
+    });
+}
+
+// <region> [metric], where the region may be a multi-word state name like "new york"
+fn parse_region(args: Args) -> Result<Command, String> {
+    let mut words = args.words();
+    let compare = match words.iter().position(|w| w.to_lowercase() == "vs") {
+        Some(vs) => {
+            let compare = words[vs+1..].join(" ");
+            if compare.is_empty() {
+                return Err("Missing state to compare with. Usage: @coronabot <state> [metric] vs <state>".to_string());
+            }
+            words.truncate(vs);
+            Some(compare)
+        },
+        None => None,
+    };
+    if words.is_empty() {
+        return Err("Missing state. Try @coronabot help".to_string());
+    }
+    let (region, metric) = if words.len() == 1 || regions::resolve(&words.join(" ")).is_ok() {
+        (words.join(" "), None)
+    } else if words.len() == 2 || regions::resolve(&words[..words.len()-1].join(" ")).is_ok() {
+        (words[..words.len()-1].join(" "), Some(words[words.len()-1].to_string()))
+    } else {
+        return Err("Sorry, I didn't understand that. Try @coronabot help".to_string());
+    };
+    return Ok(Command::State{region: region, compare: compare, metric: metric, range: args.range, spec: args.spec});
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, NaiveDate};
+
+    fn parse_text(text: &str) -> Result<Command, String> {
+        return parse(text, ChartSpec::default());
+    }
+
+    #[test]
+    fn help() {
+        assert!(matches!(parse_text("help"), Ok(Command::Help{topic: None})));
+        assert!(matches!(parse_text("HELP Custom"), Ok(Command::Help{topic: Some(ref topic)}) if topic == "custom"));
+        assert!(parse_text("help nonsense").is_err());
+        assert!(parse_text("help custom latest").is_err());
+    }
+
+    #[test]
+    fn define_and_undefine() {
+        match parse_text("define positivity = positive / total") {
+            Ok(Command::Define{name, expression}) => {
+                assert_eq!(name, "positivity");
+                assert_eq!(expression, "positive / total");
+            },
+            other => panic!("{:?}", other),
+        }
+        assert!(parse_text("define positivity positive").is_err());
+        assert!(parse_text("define positivity =").is_err());
+        assert!(matches!(parse_text("undefine positivity"), Ok(Command::Undefine{ref name}) if name == "positivity"));
+        assert!(parse_text("undefine").is_err());
+        assert!(parse_text("undefine a b").is_err());
+    }
+
+    #[test]
+    fn commands_without_arguments() {
+        assert!(matches!(parse_text("list"), Ok(Command::List)));
+        assert!(matches!(parse_text("top"), Ok(Command::Top)));
+        assert!(matches!(parse_text("subscriptions"), Ok(Command::Subscriptions)));
+        assert!(matches!(parse_text("alerts"), Ok(Command::Alerts)));
+        for text in ["list all", "top 10", "subscriptions here", "alerts CA"].iter() {
+            assert!(parse_text(text).is_err(), "{:}", text);
+        }
+    }
+
+    #[test]
+    fn theme() {
+        assert!(matches!(parse_text("theme"), Ok(Command::Theme{theme: None})));
+        assert!(matches!(parse_text("theme dark"), Ok(Command::Theme{theme: Some(Theme::Dark)})));
+        assert!(parse_text("theme purple").is_err());
+        assert!(parse_text("theme light dark").is_err());
+    }
+
+    #[test]
+    fn latest() {
+        match parse_text("latest positive last 2w") {
+            Ok(Command::Latest{metric, range, ..}) => {
+                assert_eq!(metric.as_deref(), Some("positive"));
+                assert_eq!(range, DateRange::Last(Duration::days(14)));
+            },
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(parse_text("latest"), Ok(Command::Latest{metric: None, range: DateRange::All, ..})));
+        assert!(parse_text("latest positive dead").is_err());
+    }
+
+    #[test]
+    fn state() {
+        match parse_text("new york dead") {
+            Ok(Command::State{region, compare, metric, ..}) => {
+                assert_eq!(region, "new york");
+                assert_eq!(compare, None);
+                assert_eq!(metric.as_deref(), Some("dead"));
+            },
+            other => panic!("{:?}", other),
+        }
+        match parse_text("new york") {
+            Ok(Command::State{region, metric, ..}) => {
+                assert_eq!(region, "new york");
+                assert_eq!(metric, None);
+            },
+            other => panic!("{:?}", other),
+        }
+        match parse_text("CA+OR+WA positive since 2020-06-01") {
+            Ok(Command::State{region, metric, range, ..}) => {
+                assert_eq!(region, "CA+OR+WA");
+                assert_eq!(metric.as_deref(), Some("positive"));
+                assert_eq!(range, DateRange::Since(NaiveDate::from_ymd(2020, 6, 1)));
+            },
+            other => panic!("{:?}", other),
+        }
+        assert!(parse_text("what is going on here").is_err());
+    }
+
+    #[test]
+    fn state_vs() {
+        match parse_text("CA positive vs new york last 30d") {
+            Ok(Command::State{region, compare, metric, range, ..}) => {
+                assert_eq!(region, "CA");
+                assert_eq!(compare.as_deref(), Some("new york"));
+                assert_eq!(metric.as_deref(), Some("positive"));
+                assert_eq!(range, DateRange::Last(Duration::days(30)));
+            },
+            other => panic!("{:?}", other),
+        }
+        assert!(parse_text("CA vs").is_err());
+        assert!(parse_text("vs CA").is_err());
+    }
+
+    #[test]
+    fn custom() {
+        match parse_text("custom new york y1 log(dead) since 2020-04-01") {
+            Ok(Command::Custom{region, expression, range, ..}) => {
+                assert_eq!(region, "new york");
+                assert_eq!(expression, "log(dead)");
+                assert_eq!(range, DateRange::Since(NaiveDate::from_ymd(2020, 4, 1)));
+            },
+            other => panic!("{:?}", other),
+        }
+        // Only the first y1 after the region counts, later ones are part of the expression
+        match parse_text("custom CA Y1 y1 + positive") {
+            Ok(Command::Custom{region, expression, ..}) => {
+                assert_eq!(region, "CA");
+                assert_eq!(expression, "y1 + positive");
+            },
+            other => panic!("{:?}", other),
+        }
+        // The region can't be empty, so a leading y1 is taken as the region
+        match parse_text("custom y1 y1 positive") {
+            Ok(Command::Custom{region, expression, ..}) => {
+                assert_eq!(region, "y1");
+                assert_eq!(expression, "positive");
+            },
+            other => panic!("{:?}", other),
+        }
+        assert!(parse_text("custom CA positive").is_err());
+        assert!(parse_text("custom CA y1").is_err());
+        assert!(parse_text("custom CA y1 positive from 2020-06-01").is_err());
+    }
+
+    #[test]
+    fn subscribe() {
+        match parse_text("subscribe new york positive weekdays at 8:30 pm America/Chicago") {
+            Ok(Command::Subscribe{query, schedule, timezone, description}) => {
+                assert_eq!(query, "new york positive");
+                assert_eq!(schedule, "30 20 * * 1-5");
+                assert_eq!(timezone.as_deref(), Some("America/Chicago"));
+                assert_eq!(description, "on weekdays at 20:30");
+            },
+            other => panic!("{:?}", other),
+        }
+        match parse_text("subscribe top mondays 17:00 utc") {
+            Ok(Command::Subscribe{query, schedule, timezone, ..}) => {
+                assert_eq!(query, "top");
+                assert_eq!(schedule, "0 17 * * 1");
+                assert_eq!(timezone.as_deref(), Some("UTC"));
+            },
+            other => panic!("{:?}", other),
+        }
+        match parse_text("subscribe latest 12am") {
+            Ok(Command::Subscribe{schedule, timezone, description, ..}) => {
+                assert_eq!(schedule, "0 0 * * *");
+                assert_eq!(timezone, None);
+                assert_eq!(description, "every day at 0:00");
+            },
+            other => panic!("{:?}", other),
+        }
+        for text in ["subscribe", "subscribe CA 9", "subscribe CA 13pm", "subscribe daily 9am", "subscribe help 9am",
+                     "subscribe CA 9am Mars/Olympus_Mons"].iter() {
+            assert!(parse_text(text).is_err(), "{:}", text);
+        }
+    }
+
+    #[test]
+    fn unsubscribe_and_unalert() {
+        assert!(matches!(parse_text("unsubscribe 2"), Ok(Command::Unsubscribe{ref which}) if which == "2"));
+        assert!(matches!(parse_text("unsubscribe new york positive"), Ok(Command::Unsubscribe{ref which}) if which == "new york positive"));
+        assert!(parse_text("unsubscribe").is_err());
+        assert!(matches!(parse_text("unalert all"), Ok(Command::Unalert{ref which}) if which == "all"));
+        assert!(parse_text("unalert").is_err());
+    }
+
+    #[test]
+    fn alert() {
+        match parse_text("alert new york dead rises 20% week-over-week") {
+            Ok(Command::Alert{region, metric, condition}) => {
+                assert_eq!(region, "new york");
+                assert_eq!(metric, "dead");
+                assert_eq!(condition, Condition::Rises{percent: 20.0});
+            },
+            other => panic!("{:?}", other),
+        }
+        match parse_text("alert CA positivity > 10%") {
+            Ok(Command::Alert{region, metric, condition}) => {
+                assert_eq!(region, "CA");
+                assert_eq!(metric, "positivity");
+                assert_eq!(condition, Condition::Above{level: 0.1, percent: true});
+            },
+            other => panic!("{:?}", other),
+        }
+        match parse_text("alert CA down below 5") {
+            Ok(Command::Alert{region, metric, condition}) => {
+                assert_eq!(region, "CA");
+                assert_eq!(metric, "down");
+                assert_eq!(condition, Condition::Below{level: 5.0, percent: false});
+            },
+            other => panic!("{:?}", other),
+        }
+        for text in ["alert", "alert CA positive", "alert positive > 10", "alert > positive 10", "alert CA positive > lots", "alert CA positive rises 20% forever"].iter() {
+            assert!(parse_text(text).is_err(), "{:}", text);
+        }
+    }
+
+    #[test]
+    fn rest_of_words() {
+        assert_eq!(rest_of("  <@U1>  custom CA y1  positive", 2), "CA y1  positive");
+        assert_eq!(rest_of("top", 1), "");
+    }
+}
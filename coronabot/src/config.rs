@@ -0,0 +1,298 @@
+use crate::regions;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+// Deployment settings, read from a JSON file. Every field has a default so a missing
+// file (or a partial one) still gives a working bot.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Config {
+    #[serde(default = "default_store_path")]
+    pub store_path: String,
+
+    // Named sets of states that can be used anywhere a state is expected, e.g. "west"
+    #[serde(default = "default_groups")]
+    pub groups: HashMap<String, Vec<String>>,
+
+    // Which chart renderer to use: "gnuplot", "plotters" (in-process, no binaries needed) or
+    // "auto" to use gnuplot when it's installed
+    #[serde(default = "default_renderer")]
+    pub renderer: String,
+
+    #[serde(default)]
+    pub image_store: ImageStoreConfig,
+
+    #[serde(default)]
+    pub chart_cache: ChartCacheConfig,
+
+    #[serde(default)]
+    pub workers: WorkersConfig,
+
+    #[serde(default)]
+    pub transport: TransportConfig,
+
+    #[serde(default)]
+    pub briefings: Vec<BriefingConfig>,
+
+    // The timezone for subscriptions that don't name one, e.g. "America/New_York"
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+}
+
+// How messages get from Slack to the bot
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TransportConfig {
+    // The legacy RTM websocket, only available to classic Slack apps
+    Rtm,
+    // Slack POSTs events to an HTTP endpoint, e.g. listen "0.0.0.0:3000" behind a public URL
+    // ending in path. Slash commands and interactions (buttons and menus) are POSTed to
+    // commands_path and interactions_path on the same server. The signing secret is on the app's
+    // Basic Information page.
+    Events {
+        listen: String,
+        #[serde(default = "default_events_path")]
+        path: String,
+        #[serde(default = "default_commands_path")]
+        commands_path: String,
+        #[serde(default = "default_interactions_path")]
+        interactions_path: String,
+        signing_secret: String,
+    },
+    // A websocket opened by the bot, so nothing has to be exposed to the internet. Needs an
+    // app-level token (xapp-...) with connections:write.
+    Socket {
+        app_token: String,
+    },
+}
+
+impl Default for TransportConfig {
+    fn default() -> TransportConfig {
+        return TransportConfig::Rtm;
+    }
+}
+
+fn default_events_path() -> String {
+    return "/slack/events".to_string();
+}
+
+fn default_commands_path() -> String {
+    return "/slack/commands".to_string();
+}
+
+fn default_interactions_path() -> String {
+    return "/slack/interactions".to_string();
+}
+
+// A briefing posted to a channel on a schedule, once there's data it hasn't covered yet
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BriefingConfig {
+    pub channel: String,
+
+    // Cron-style "minute hour day-of-month month day-of-week", e.g. "0 9 * * 1-5" for 9am on
+    // weekdays
+    pub schedule: String,
+
+    // Which timezone schedule is in, e.g. "America/New_York"
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+
+    // Run in order as if they'd been sent to the bot, e.g. "latest" for the U.S. summary, "CA" for a
+    // state's chart and "top" for the leaderboard
+    #[serde(default = "default_briefing_commands")]
+    pub commands: Vec<String>,
+}
+
+fn default_timezone() -> String {
+    return "UTC".to_string();
+}
+
+fn default_briefing_commands() -> Vec<String> {
+    return vec!["latest".to_string(), "top".to_string()];
+}
+
+// How commands are run in the background so the bot keeps listening while charts render
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorkersConfig {
+    #[serde(default = "default_threads")]
+    pub threads: usize,
+
+    // Commands waiting for a worker beyond this are turned away
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+
+    // How long a command can take before giving up on it, also applies to each gnuplot process
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+
+    #[serde(default = "default_gnuplot_processes")]
+    pub gnuplot_processes: usize,
+}
+
+impl Default for WorkersConfig {
+    fn default() -> WorkersConfig {
+        return WorkersConfig{
+            threads: default_threads(),
+            queue_size: default_queue_size(),
+            timeout_secs: default_timeout_secs(),
+            gnuplot_processes: default_gnuplot_processes(),
+        };
+    }
+}
+
+fn default_threads() -> usize {
+    return 4;
+}
+
+fn default_queue_size() -> usize {
+    return 32;
+}
+
+fn default_timeout_secs() -> u64 {
+    return 60;
+}
+
+fn default_gnuplot_processes() -> usize {
+    return 2;
+}
+
+// Reuse of already-uploaded charts until the data next changes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChartCacheConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+
+    // How long charts from before the latest data refresh are kept in the image store before
+    // being deleted. Links posted earlier stop working after this.
+    #[serde(default = "default_retention_hours")]
+    pub retention_hours: u64,
+}
+
+impl Default for ChartCacheConfig {
+    fn default() -> ChartCacheConfig {
+        return ChartCacheConfig{enabled: true, retention_hours: default_retention_hours()};
+    }
+}
+
+fn default_true() -> bool {
+    return true;
+}
+
+fn default_retention_hours() -> u64 {
+    return 24 * 7;
+}
+
+// Where rendered charts are uploaded to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ImageStoreConfig {
+    // AWS S3, or anything speaking its API (MinIO, localstack) when endpoint is set. Credentials
+    // fall back to the usual AWS environment variables and profile when not given here.
+    S3 {
+        bucket: String,
+        #[serde(default = "default_s3_region")]
+        region: String,
+        #[serde(default)]
+        endpoint: Option<String>,
+        #[serde(default)]
+        prefix: String,
+        #[serde(default)]
+        public_url: Option<String>,
+        #[serde(default)]
+        access_key: Option<String>,
+        #[serde(default)]
+        secret_key: Option<String>,
+    },
+    // A local directory, served over HTTP on listen (e.g. "0.0.0.0:8080") when set
+    Local {
+        dir: String,
+        public_url: String,
+        #[serde(default)]
+        listen: Option<String>,
+    },
+    // Uploaded to Slack and attached to the reply, needs the files:write scope
+    Slack,
+}
+
+fn default_s3_region() -> String {
+    return "us-east-1".to_string();
+}
+
+impl Default for ImageStoreConfig {
+    fn default() -> ImageStoreConfig {
+        return ImageStoreConfig::S3{
+            bucket: "image-paster".to_string(),
+            region: default_s3_region(),
+            endpoint: None,
+            prefix: "coronavirus/".to_string(),
+            public_url: None,
+            access_key: None,
+            secret_key: None,
+        };
+    }
+}
+
+fn default_store_path() -> String {
+    return "coronabot_store.json".to_string();
+}
+
+fn default_renderer() -> String {
+    return "auto".to_string();
+}
+
+// U.S. Census Bureau regions
+fn default_groups() -> HashMap<String, Vec<String>> {
+    let mut groups = HashMap::new();
+    groups.insert("northeast", vec!["CT", "ME", "MA", "NH", "RI", "VT", "NJ", "NY", "PA"]);
+    groups.insert("midwest", vec!["IL", "IN", "MI", "OH", "WI", "IA", "KS", "MN", "MO", "NE", "ND", "SD"]);
+    groups.insert("south", vec!["DE", "DC", "FL", "GA", "MD", "NC", "SC", "VA", "WV", "AL", "KY", "MS", "TN", "AR", "LA", "OK", "TX"]);
+    groups.insert("west", vec!["AZ", "CO", "ID", "MT", "NV", "NM", "UT", "WY", "AK", "CA", "HI", "OR", "WA"]);
+    return groups.into_iter()
+        .map(|(name, states)| (name.to_string(), states.iter().map(|s| s.to_string()).collect()))
+        .collect();
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        return serde_json::from_str("{}").unwrap();
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> Config {
+        match fs::read_to_string(path) {
+            Ok(body) => {
+                let mut config: Config = match serde_json::from_str(&body) {
+                    Ok(config) => config,
+                    Err(err) => panic!("Failed to parse config {:}: {:}", path, err),
+                };
+                config.groups = match normalize_groups(&config.groups) {
+                    Ok(groups) => groups,
+                    Err(err) => panic!("Bad groups in config {:}: {:}", path, err),
+                };
+                config
+            },
+            Err(_) => {
+                println!("No config found at {:}, using defaults", path);
+                Config::default()
+            }
+        }
+    }
+}
+
+// Regions are looked up lowercased, so store group names that way. Members can be written however
+// a state can be asked for (CA, ca, california) and are stored as the state's code.
+fn normalize_groups(groups: &HashMap<String, Vec<String>>) -> Result<HashMap<String, Vec<String>>, String> {
+    let mut normalized = HashMap::new();
+    for (name, members) in groups.iter() {
+        let mut states = Vec::new();
+        for member in members.iter() {
+            let state = regions::resolve(member).map_err(|err| format!("{member} in {name}: {err}", member=member, name=name, err=err))?;
+            states.push(state.to_string());
+        }
+        if normalized.insert(name.trim().to_lowercase(), states).is_some() {
+            return Err(format!("{name} is defined more than once", name=name.trim().to_lowercase()));
+        }
+    }
+    return Ok(normalized);
+}
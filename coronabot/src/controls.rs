@@ -0,0 +1,177 @@
+use crate::chart::Scale;
+use crate::command::Command;
+use crate::daterange::{self, DateRange};
+use crate::regions;
+use chrono::Duration;
+use serde_json::Value;
+
+// Block ids are limited to 255 characters, and carry the command along with this prefix
+const BLOCK_ID_PREFIX: &str = "chart|";
+const MAX_BLOCK_ID: usize = 255;
+
+const WINDOWS: [i64; 3] = [7, 14, 30];
+
+// Buttons and a menu under a chart for changing it without retyping the command. The command
+// travels with the message in the block id, and each control's value says how to change it.
+pub fn actions_block(query: &str, command: &Command) -> Option<Value> {
+    let block_id = format!("{prefix}{query}", prefix=BLOCK_ID_PREFIX, query=query);
+    if block_id.len() > MAX_BLOCK_ID {
+        return None;
+    }
+    let (range, spec) = match command {
+        Command::Latest{range, spec, ..} => (range, spec),
+        Command::State{range, spec, ..} => (range, spec),
+        Command::Custom{range, spec, ..} => (range, spec),
+        _ => return None,
+    };
+
+    let mut elements = Vec::new();
+    for days in WINDOWS.iter() {
+        let selected = *range == DateRange::Last(Duration::days(*days));
+        elements.push(button(&format!("range_{days}", days=days), &format!("{days} days", days=days), &format!("range:{days}", days=days), selected));
+    }
+    elements.push(button("scale_linear", "Linear", "scale:linear", spec.y1_scale == Scale::Linear));
+    elements.push(button("scale_log", "Log", "scale:log", spec.y1_scale == Scale::Log));
+
+    // Comparing only makes sense for a state against another
+    match command {
+        Command::State{..} => {
+            let options: Vec<Value> = regions::STATES.iter()
+                .map(|(code, name, _)| serde_json::json!({
+                    "text": {"type": "plain_text", "text": name},
+                    "value": format!("compare:{code}", code=code),
+                }))
+                .collect();
+            elements.push(serde_json::json!({
+                "type": "static_select",
+                "action_id": "compare",
+                "placeholder": {"type": "plain_text", "text": "Compare with…"},
+                "options": options,
+            }));
+        },
+        _ => {}
+    }
+
+    return Some(serde_json::json!({"type": "actions", "block_id": block_id, "elements": elements}));
+}
+
+// Gets the command back out of the block id of a control that was used
+pub fn query_from_block_id(block_id: &str) -> Option<&str> {
+    if !block_id.starts_with(BLOCK_ID_PREFIX) {
+        return None;
+    }
+    return Some(&block_id[BLOCK_ID_PREFIX.len()..]);
+}
+
+// Rewrites a command as changed by a control: "range:<days>", "scale:<log|linear>" or
+// "compare:<state>". The date range and chart options are moved to the end on the way.
+pub fn apply(query: &str, action: &str) -> Result<String, String> {
+    let (rest, mut range) = daterange::extract(query)?;
+    let (mut options, mut words): (Vec<&str>, Vec<&str>) = rest.split_whitespace().partition(|w| w.contains("="));
+    let scale_option;
+    let compare_with;
+
+    let colon = action.find(":").ok_or(format!("Unknown action {:}", action))?;
+    let value = &action[colon+1..];
+    match &action[..colon] {
+        "range" => {
+            let days = value.parse::<i64>().map_err(|_| format!("Unknown window {:}", value))?;
+            range = DateRange::Last(Duration::days(days));
+        },
+        "scale" => {
+            options.retain(|o| !o.to_lowercase().starts_with("scale="));
+            scale_option = format!("scale={value}", value=value);
+            options.push(&scale_option);
+        },
+        "compare" => {
+            match words.iter().position(|w| w.to_lowercase() == "vs") {
+                Some(vs) => words.truncate(vs),
+                None => {}
+            }
+            compare_with = format!("vs {value}", value=value);
+            words.push(&compare_with);
+        },
+        _ => return Err(format!("Unknown action {:}", action)),
+    }
+
+    let range_words = range.to_words();
+    words.push(&range_words);
+    words.extend(options);
+    let words: Vec<&str> = words.into_iter().filter(|w| !w.is_empty()).collect();
+    return Ok(words.join(" "));
+}
+
+fn button(action_id: &str, text: &str, value: &str, selected: bool) -> Value {
+    let mut button = serde_json::json!({
+        "type": "button",
+        "action_id": action_id,
+        "text": {"type": "plain_text", "text": text},
+        "value": value,
+    });
+    if selected {
+        button["style"] = Value::from("primary");
+    }
+    return button;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chart::ChartSpec;
+    use crate::command;
+
+    #[test]
+    fn changes_window() {
+        assert_eq!(apply("CA last 30d", "range:14"), Ok("CA last 14d".to_string()));
+        assert_eq!(apply("CA positive", "range:7"), Ok("CA positive last 7d".to_string()));
+        assert_eq!(apply("CA since 2020-06-01 scale=log", "range:30"), Ok("CA last 30d scale=log".to_string()));
+        assert!(apply("CA", "range:soon").is_err());
+    }
+
+    #[test]
+    fn replaces_scale() {
+        assert_eq!(apply("CA scale=log last 14d", "scale:linear"), Ok("CA last 14d scale=linear".to_string()));
+        assert_eq!(apply("CA Scale=LOG theme=dark", "scale:log"), Ok("CA theme=dark scale=log".to_string()));
+        assert_eq!(apply("CA", "scale:log"), Ok("CA scale=log".to_string()));
+    }
+
+    #[test]
+    fn replaces_compare() {
+        assert_eq!(apply("CA positive last 7d", "compare:TX"), Ok("CA positive vs TX last 7d".to_string()));
+        assert_eq!(apply("CA positive vs new york last 7d", "compare:TX"), Ok("CA positive vs TX last 7d".to_string()));
+        assert_eq!(apply("CA VS TX", "compare:OR"), Ok("CA vs OR".to_string()));
+    }
+
+    #[test]
+    fn rejects_unknown_actions() {
+        assert!(apply("CA", "zoom:2").is_err());
+        assert!(apply("CA", "range").is_err());
+    }
+
+    #[test]
+    fn round_trips_through_block_id() {
+        let query = "CA positive last 30d";
+        let command = command::parse(query, ChartSpec::default()).unwrap();
+        let block = actions_block(query, &command).unwrap();
+        let block_id = block["block_id"].as_str().unwrap();
+        assert_eq!(query_from_block_id(block_id), Some(query));
+        assert_eq!(query_from_block_id("other|CA"), None);
+        // The 30 day window is the one selected, and every state can be compared against
+        let elements = block["elements"].as_array().unwrap();
+        assert_eq!(elements[2]["style"], "primary");
+        assert!(elements[0].get("style").is_none());
+        assert_eq!(elements.last().unwrap()["options"].as_array().unwrap().len(), regions::STATES.len());
+        let changed = apply(query_from_block_id(block_id).unwrap(), "range:14").unwrap();
+        assert_eq!(changed, "CA positive last 14d");
+        assert!(command::parse(&changed, ChartSpec::default()).is_ok());
+    }
+
+    #[test]
+    fn only_charts_get_controls() {
+        let command = command::parse("list", ChartSpec::default()).unwrap();
+        assert!(actions_block("list", &command).is_none());
+        let query = format!("CA {:}", "x".repeat(MAX_BLOCK_ID));
+        let command = command::parse("CA", ChartSpec::default()).unwrap();
+        assert!(actions_block(&query, &command).is_none());
+    }
+}
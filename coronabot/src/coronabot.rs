@@ -1,28 +1,36 @@
 use crate::{USDAILY_URL, STATESDAILY_URL};
-use slack::{Event, RtmClient, Message};
 use serde_json::Value;
 use serde::{Deserialize, Serialize};
 use std::thread;
 use std::time::Duration;
 use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use num_format::{Locale, ToFormattedString};
-use chrono::{DateTime, Utc, FixedOffset, NaiveDate, NaiveTime};
-use gnuplot::{Figure, Caption, Color, AxesCommon, DashType};
+use chrono::{DateTime, Utc, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime};
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
-use gnuplot::AutoOption::{Fix, Auto};
-use gnuplot::TickOption::{Mirror, Format};
-use gnuplot::LabelOption::{Font, TextColor};
 use uuid::Uuid;
-use s3::bucket::Bucket;
-use s3::credentials::Credentials;
-use std::fs::File;
-use std::io::Read;
-use gnuplot::PlotOption::{Axes, LineStyle, PointSize};
-use gnuplot::XAxis::X1;
-use gnuplot::YAxis::{Y1, Y2};
-use mexprp::{Term, Context, Calculation, MathError, Answer};
-
+use crate::blocks::Message;
+use crate::expressions;
+use crate::regions;
+use crate::reply::Reply;
+use crate::slack_web::{Identity, SlackWeb};
+use crate::store::{Alert, Store, Subscription};
+use crate::config::{Config, TransportConfig};
+use crate::daterange::DateRange;
+use crate::alerts::Condition;
+use crate::chart::{Chart, ChartSpec, LegendPosition, Theme};
+use crate::command::{self, Command};
+use crate::controls;
+use crate::chart_cache::{self, ChartCache, ChartLink};
+use crate::render::{self, ChartRenderer, ImageFormat, RenderedChart};
+use crate::image_store::{self, ChartUpload, ImageStore, Stored};
+
+// Credited in the footer of anything showing the data
+const DATA_SOURCE: &str = "<https://covidtracking.com|The COVID Tracking Project>";
+// So a channel can't have the bot posting all day
+const MAX_SUBSCRIPTIONS: usize = 10;
+const MAX_ALERTS: usize = 20;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct DailyStats {
@@ -37,9 +45,20 @@ struct DailyStats {
 }
 
 pub struct Coronabot {
-    bot_id: String,
+    identity: Identity,
     us_daily: Arc<RwLock<Option<Vec<DailyStats>>>>,
-    states_daily: Arc<RwLock<Option<HashMap<String, Vec<DailyStats>>>>>
+    states_daily: Arc<RwLock<Option<HashMap<String, Vec<DailyStats>>>>>,
+    store: Arc<RwLock<Store>>,
+    config: Config,
+    renderer: Box<dyn ChartRenderer>,
+    image_store: Arc<dyn ImageStore>,
+    web: SlackWeb,
+
+    // Bumped every time fresh data is fetched, so cached charts know when they're out of date
+    data_version: Arc<AtomicUsize>,
+    // The most recent day in the U.S. data
+    latest_date: Arc<RwLock<Option<NaiveDate>>>,
+    chart_cache: Arc<RwLock<ChartCache>>
 
     // TODO: Should have a list of data sources that can be accessed
 }
@@ -60,13 +79,144 @@ fn construct_states_map(data: &Vec<DailyStats>) -> HashMap<String, Vec<DailyStat
     return ret;
 }
 
+// Sums counts across states for each day. Only days every state reported are kept, otherwise
+// a state starting to report mid-series shows up as a huge one-day jump.
+fn aggregate_states(series: &Vec<&Vec<DailyStats>>) -> Vec<DailyStats> {
+    let mut by_date: HashMap<u32, (usize, DailyStats)> = HashMap::new();
+    for state_data in series.iter() {
+        for dp in state_data.iter() {
+            let date = match dp.date {
+                Some(date) => date,
+                None => continue,
+            };
+            let entry = by_date.entry(date).or_insert((0, DailyStats {
+                state: None,
+                date: Some(date),
+                positive: None,
+                negative: None,
+                pending: None,
+                hospitalized: None,
+                death: None,
+                total: None,
+            }));
+            entry.0 += 1;
+            let sum = &mut entry.1;
+            sum.positive = add_counts(sum.positive, dp.positive);
+            sum.negative = add_counts(sum.negative, dp.negative);
+            sum.pending = add_counts(sum.pending, dp.pending);
+            sum.hospitalized = add_counts(sum.hospitalized, dp.hospitalized);
+            sum.death = add_counts(sum.death, dp.death);
+            sum.total = add_counts(sum.total, dp.total);
+        }
+    }
+
+    let mut ret: Vec<DailyStats> = by_date.into_iter()
+        .filter(|(_, (count, _))| *count == series.len())
+        .map(|(_, (_, dp))| dp)
+        .collect();
+    // Newest first, matching the API
+    ret.sort_by(|a, b| b.date.cmp(&a.date));
+    return ret;
+}
+
+fn add_counts(a: Option<u32>, b: Option<u32>) -> Option<u32> {
+    match (a, b) {
+        (None, None) => None,
+        _ => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+    }
+}
+
+// Keeps only the points of a chart's series whose date (x, as a timestamp) falls in range
+fn slice_series(x: Vec<i64>, y1: Vec<f32>, y2: Vec<f32>, range: &DateRange) -> (Vec<i64>, Vec<f32>, Vec<f32>) {
+    let latest = match x.iter().max() {
+        Some(latest) => NaiveDateTime::from_timestamp(*latest, 0).date(),
+        None => return (x, y1, y2),
+    };
+    let mut sliced_x = Vec::new();
+    let mut sliced_y1 = Vec::new();
+    let mut sliced_y2 = Vec::new();
+    for (i, ts) in x.iter().enumerate() {
+        let date = NaiveDateTime::from_timestamp(*ts, 0).date();
+        if !range.contains(date, latest) {
+            continue;
+        }
+        sliced_x.push(*ts);
+        match y1.get(i) {
+            Some(v) => sliced_y1.push(*v),
+            None => {}
+        }
+        match y2.get(i) {
+            Some(v) => sliced_y2.push(*v),
+            None => {}
+        }
+    }
+    return (sliced_x, sliced_y1, sliced_y2);
+}
+
+// Day-over-day change in a cumulative count, e.g. +5%. Counts do sometimes go down, and there's
+// nothing to compare with when the day before is missing or zero.
+fn format_change(today: Option<u32>, yesterday: Option<u32>) -> String {
+    let (today, yesterday) = match (today, yesterday) {
+        (Some(today), Some(yesterday)) if yesterday > 0 => (today as i64, yesterday as i64),
+        _ => return "n/a".to_string(),
+    };
+    let change = (today - yesterday) as f64 / yesterday as f64 * 100.0;
+    return format!("{change:+.0}%", change=change);
+}
+
+fn data_footer(date: Option<NaiveDate>) -> String {
+    match date {
+        Some(date) => return format!("Data for {date} from {source}", date=date, source=DATA_SOURCE),
+        None => return format!("Data from {source}", source=DATA_SOURCE),
+    }
+}
+
+// Puts other's first series against the right-hand axis of chart, lined up by date
+fn compare_charts(title: String, chart: Chart, label: &str, other: Chart, other_label: &str) -> Chart {
+    let other_values: HashMap<i64, f32> = other.x.iter().cloned().zip(other.y1.iter().cloned()).collect();
+    let y2 = chart.x.iter()
+        .map(|x| *other_values.get(x).unwrap_or(&std::f32::NAN))
+        .collect();
+    return Chart{title: title, x: chart.x, y1: chart.y1, y2: y2, y1_label: label.to_string(), y2_label: other_label.to_string()};
+}
+
+// A comparison needs its second series and a legend to tell them apart
+fn comparison_spec(spec: &ChartSpec) -> ChartSpec {
+    let mut spec = spec.clone();
+    spec.show_y2 = true;
+    if spec.legend == LegendPosition::Hidden {
+        spec.legend = LegendPosition::TopLeft;
+    }
+    return spec;
+}
+
+fn titled_range(title: &str, range: &DateRange) -> String {
+    match range {
+        DateRange::All => title.to_string(),
+        _ => format!("{title} ({range})", title=title, range=range.describe()),
+    }
+}
 
 impl Coronabot {
-    pub fn new(bot_id: String) -> Coronabot {
-        return Coronabot{bot_id: bot_id, us_daily: Arc::new(RwLock::new(None)), states_daily: Arc::new(RwLock::new(None))};
+    pub fn new(identity: Identity, token: String, config: Config) -> Coronabot {
+        let store = Store::load(&config.store_path);
+        let renderer = render::from_config(&config.renderer, &config.workers);
+        let image_store = image_store::from_config(&config.image_store, &token);
+        let chart_cache = ChartCache::new(config.chart_cache.retention_hours);
+        return Coronabot{identity: identity,
+                         us_daily: Arc::new(RwLock::new(None)),
+                         states_daily: Arc::new(RwLock::new(None)),
+                         store: Arc::new(RwLock::new(store)),
+                         config: config,
+                         renderer: renderer,
+                         image_store: Arc::from(image_store),
+                         web: SlackWeb::new(&token),
+                         data_version: Arc::new(AtomicUsize::new(0)),
+                         latest_date: Arc::new(RwLock::new(None)),
+                         chart_cache: Arc::new(RwLock::new(chart_cache))};
     }
 
-    fn format_high_scores(&self, data: &HashMap<String, Vec<DailyStats>>) -> String {
+    fn format_high_scores(&self, data: &HashMap<String, Vec<DailyStats>>) -> Message {
         let mut pos_growth = 0;
         let mut pos_growth_state = "".to_string();
 
@@ -132,82 +282,129 @@ impl Coronabot {
             }
         }
 
-        return format!("
-        Daily Worsts ({date})\n \
-        Mortality rate: {mortality_rate_state} ({mortality_rate:.2}%)\n \
-        Positive tests growth: {pos_growth_state} (+{pos_growth}%)\n \
-        Deaths growth: {death_growth_state} (+{death_growth}%)",
-                       date=date,
-                       mortality_rate=mortality_rate,
-                       mortality_rate_state=mortality_rate_state,
-                       pos_growth=pos_growth,
-                       pos_growth_state=pos_growth_state,
-                       death_growth=death_growth,
-                       death_growth_state = death_growth_state);
-
-    }
-
-    fn generate_chart(&self,
-                      x: Vec<i64>,
-                      y1: Vec<f32>,
-                      y2: Vec<f32>,
-                      title: &str,
-                      x1_name: &str,
-                      y1_name: &str,
-                      y2_name: &str) -> String {
-        let mut fg = Figure::new();
-        fg.axes2d()
-            .set_title(&title, &[])
-            .lines_points(
-                &x,
-                &y1,
-                &[Axes(X1, Y1), Color("black"), PointSize(0.0)],
-            )
-            /*
-            .lines_points(
-                &x,
-                &y2,
-                &[Axes(X1, Y2), Color("blue"), PointSize(0.0)],
-            )
-            */
-            .set_y_ticks(Some((Auto, 0)), &[Mirror(false)], &[])  // Make Y1 not mirror.
-            // .set_y2_ticks(Some((Auto, 0)), &[Mirror(false), Format("%.2f")], &[])  // Make Y2 not mirror, and visible.
-            .set_y_label(y1_name, &[TextColor("black")])
-            // .set_y2_label(y2_name, &[TextColor("blue")])
-            .set_x_ticks(Some((Auto, 1)), &[Mirror(false), Format("%m/%d")], &[Font("Helvetica", 12.0)])
-            .set_x_time(true);
-
-
-        println!("Saving to disk...");
-        let mut fpath = "/tmp/".to_string();
-        let uuid = Uuid::new_v4().to_string();
-        fpath.push_str(&uuid);
-        fpath.push_str(".png");
-        let mut s3_path = "coronavirus/".to_string();
-        s3_path.push_str(&uuid);
-        s3_path.push_str(".png");
-        let res = fg.save_to_png(&fpath.to_string(), 800, 400);
+        let fields = [
+            ("Mortality rate", format!("{state} ({rate:.2}%)", state=mortality_rate_state, rate=mortality_rate)),
+            ("Positive tests growth", format!("{state} (+{growth}%)", state=pos_growth_state, growth=pos_growth)),
+            ("Deaths growth", format!("{state} (+{growth}%)", state=death_growth_state, growth=death_growth)),
+        ];
+        let title = format!("Daily Worsts ({date})", date=date);
+        let mut text = title.clone();
+        for (label, value) in fields.iter() {
+            text.push_str(&format!("\n{label}: {value}", label=label, value=value));
+        }
+        return Message::new(&text)
+            .header(&title)
+            .fields(&fields)
+            .context(&data_footer(Some(date)));
+    }
+
+    fn generate_chart(&self, chart: Chart, spec: &ChartSpec) -> Result<RenderedChart, String> {
+        let res = self.renderer.render(&chart, spec);
         match res {
-            Ok(()) => {
-                println!("Saved {:}", fpath);
-                let credentials = Credentials::default();
-                let region = s3::region::Region::UsEast1;
-                let bucket = Bucket::new("image-paster", region, credentials).unwrap();
-                let mut f = File::open(&fpath).unwrap();
-                let mut buffer = Vec::new();
-                f.read_to_end(&mut buffer).unwrap();
-                bucket.put_object_blocking(&s3_path, &buffer, "multipart/form-data");
-                let mut public_url = "https://image-paster.s3.amazonaws.com/".to_string();
-                public_url.push_str(&s3_path);
-                println!("Stored in s3: {:}", &public_url);
-                return public_url;
+            Ok(buffer) => {
+                let alt_text = format!("Chart of {y1} by day", y1=chart.y1_label);
+                return Ok(RenderedChart{title: chart.title, alt_text: alt_text, format: spec.format, bytes: buffer});
+            },
+            Err(err) => {
+                return Err(format!("Sorry, there was an error generating your plot:\n {:}", err));
+            }
+        }
+    }
+
+    // Uploads a chart (or reports why there isn't one) in reply, below summary if there is one. key
+    // identifies the request (see chart_cache::key), if the same chart was already uploaded since
+    // the last data refresh that link is sent again instead of rendering a new one.
+    fn send_chart<F>(&self, key: &str, summary: Option<Message>, actions: Option<Value>, render: F, reply: &Reply)
+        where F: FnOnce() -> Result<RenderedChart, String> {
+        let version = self.data_version.load(Ordering::SeqCst);
+        if self.config.chart_cache.enabled {
+            let cached = self.chart_cache.read().unwrap().get(key, version);
+            match cached {
+                Some(link) => {
+                    println!("Reusing cached chart {:}", link.url);
+                    reply.send_message(&self.chart_message(summary, &link, actions));
+                    return;
+                },
+                None => {}
+            }
+        }
+
+        // Posting the chart in the channel would show everyone what may have been asked privately,
+        // and can't replace a message
+        if !self.image_store.gives_urls() && !reply.in_conversation() {
+            reply.send("Sorry, charts are shared as files in the channel here, so they can only be sent in reply to @coronabot, not to a slash command.");
+            return;
+        }
+
+        reply.working();
+        let chart = match render() {
+            Ok(chart) => chart,
+            Err(err) => {
+                reply.send(&err);
+                return;
+            }
+        };
+        // Gave up waiting while it rendered, don't upload it for nothing
+        if reply.is_closed() {
+            return;
+        }
+        let mut name = Uuid::new_v4().to_string();
+        name.push_str(".");
+        name.push_str(chart.format.extension());
+        let upload = ChartUpload{
+            name: &name,
+            bytes: &chart.bytes,
+            content_type: chart.format.content_type(),
+            title: &chart.title,
+            alt_text: &chart.alt_text,
+            channel: reply.channel(),
+            thread_ts: reply.thread_ts(),
+        };
+        match self.image_store.store(&upload) {
+            Ok(Stored::Url(public_url)) => {
+                let link = ChartLink{url: public_url, title: chart.title.clone(), alt_text: chart.alt_text.clone(), format: chart.format};
+                if self.config.chart_cache.enabled {
+                    self.chart_cache.write().unwrap().insert(key, version, &name, link.clone());
+                }
+                reply.send_message(&self.chart_message(summary, &link, actions));
+            },
+            // The chart is already in the thread as a file, so only the summary is left to send
+            Ok(Stored::Posted) => {
+                match summary {
+                    Some(summary) => reply.send_message(&summary.context(&self.data_footer())),
+                    None => reply.finish(),
+                }
             },
             Err(err) => {
-                return format!("Sorry, there was an error generating your plot:\n {:?}", err);
+                println!("Failed to store chart: {:}", err);
+                let to_send = format!("Sorry, there was an error uploading your plot:\n {:}", err);
+                reply.send(&to_send);
             }
         }
     }
 
+    fn chart_message(&self, summary: Option<Message>, link: &ChartLink, actions: Option<Value>) -> Message {
+        let message = match summary {
+            Some(summary) => summary,
+            None => Message::new(&link.title),
+        };
+        // Slack can only show PNGs inline, other formats are linked
+        let mut message = message.push_text(&link.url);
+        message = match link.format {
+            ImageFormat::Png => message.image(&link.url, &link.title, &link.alt_text),
+            _ => message.section(&format!("<{url}|{title}>", url=link.url, title=link.title)),
+        };
+        match actions {
+            Some(actions) => message = message.block(actions),
+            None => {}
+        }
+        return message.context(&self.data_footer());
+    }
+
+    fn data_footer(&self) -> String {
+        return data_footer(*self.latest_date.read().unwrap());
+    }
+
     // The total count data from the API *should* be monotonically increasing with time but
     // sometimes it isn't. This is a lousy hack so I don't have to deal with it for a little while
     fn safe_diff(&self, today: Option<u32>, yesterday: Option<u32>) -> u32 {
@@ -220,8 +417,7 @@ impl Coronabot {
         return diff as u32;
     }
 
-    // TODO: Should return a Result<String, Err> so we can pass up an error from the expression parser
-    fn custom_chart(&self, data: &Vec<DailyStats>, title: String, expression: String) -> String {
+    fn custom_chart(&self, data: &Vec<DailyStats>, title: String, expression: String, range: &DateRange) -> Result<Chart, String> {
         let mut x = Vec::new();
         let mut y = Vec::new();
         let mut my_data = data.clone();
@@ -259,51 +455,23 @@ impl Coronabot {
             let deaths = today.death.unwrap_or(0);
             let hospitalized = today.hospitalized.unwrap_or(0);
             let negatives = today.negative.unwrap_or(0);
-
-            let mut context: mexprp::Context<f64> = mexprp::Context::new();
-            context.set_var("positive", positives as f64);
-            context.set_var("total", total as f64);
-            context.set_var("negative", negatives as f64);
-            context.set_var("hospitalized", hospitalized as f64);
-            context.set_var("dead", deaths as f64);
-
-            // TODO: Some refactoring
-            context.set_func("log", |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
-                if args.len() != 1 {
-                    return Err(MathError::IncorrectArguments)
-                }
-                let a = args.get(0).unwrap().eval_ctx(ctx)?;
-                let answer = match a {
-                    Answer::Single(n) => n.log2(),
-                    Answer::Multiple(ns) => ns.get(0).unwrap().log2()
-                };
-                Ok(Answer::Single(answer))
-            });
-            context.set_func("logtwo", |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
-                if args.len() != 1 {
-                    return Err(MathError::IncorrectArguments)
-                }
-                let a = args.get(0).unwrap().eval_ctx(ctx)?;
-                let answer = match a {
-                    Answer::Single(n) => n.log2(),
-                    Answer::Multiple(ns) => ns.get(0).unwrap().log2()
-                };
-                Ok(Answer::Single(answer))
-            });
-            context.set_func("logten", |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
-                if args.len() != 1 {
-                    return Err(MathError::IncorrectArguments)
+            let pending = today.pending.unwrap_or(0);
+
+            let mut values = HashMap::new();
+            values.insert("positive", positives as f64);
+            values.insert("total", total as f64);
+            values.insert("negative", negatives as f64);
+            values.insert("hospitalized", hospitalized as f64);
+            values.insert("dead", deaths as f64);
+            values.insert("pending", pending as f64);
+            let context = expressions::new_context(&values);
+
+            let expr = match mexprp::Expression::parse_ctx(&expression, context) {
+                Ok(expr) => expr,
+                Err(err) => {
+                    return Err(format!("Sorry, I couldn't parse that expression:\n {:?}", err));
                 }
-                let a = args.get(0).unwrap().eval_ctx(ctx)?;
-                let answer = match a {
-                    Answer::Single(n) => n.log10(),
-                    Answer::Multiple(ns) => ns.get(0).unwrap().log2()
-                };
-                Ok(Answer::Single(answer))
-            });
-
-
-            let expr = mexprp::Expression::parse_ctx(&expression, context).unwrap();
+            };
             let result = expr.eval();
 
             // Try to parse and evaluate the expression
@@ -328,17 +496,20 @@ impl Coronabot {
 
         }
         let y2: Vec<f32>  = Vec::new();
-        let url = self.generate_chart(x, y, y2, &title, "", &expression, "");
-        return url;
+        let (x, y, y2) = slice_series(x, y, y2, range);
+        let title = titled_range(&title, range);
+        let chart = Chart{title: title, x: x, y1: y, y2: y2, y1_label: expression, y2_label: "".to_string()};
+        return Ok(chart);
     }
 
-    fn generate_new_cases_chart(&self,  data: &Vec<DailyStats>, title: String) -> String {
+    fn new_cases_chart(&self,  data: &Vec<DailyStats>, title: String, range: &DateRange) -> Chart {
         let mut x = Vec::new();
         let mut y = Vec::new();
         let mut y2 = Vec::new();
         let mut my_data = data.clone();
         my_data.reverse();
-        for i in 6..(my_data.len() - 1) {
+        // Short or empty series (e.g. an aggregate with no common dates) just make an empty chart
+        for i in 6..my_data.len().saturating_sub(1) {
 
             let mut total_diff = 0;
             for k in i-5..i {
@@ -375,11 +546,19 @@ impl Coronabot {
             let dt = date.and_time(t);
             x.push(dt.timestamp());
         }
-        let url = self.generate_chart(x, y, y2, &title, "", "Positives", "% Positive (trailing 5 days)");
-        return url;
+        let (x, y, y2) = slice_series(x, y, y2, range);
+        let title = titled_range(&title, range);
+        let chart = Chart{title: title,
+                          x: x,
+                          y1: y,
+                          y2: y2,
+                          y1_label: "Positives".to_string(),
+                          y2_label: "% Positive (trailing 5 days)".to_string()};
+        return chart;
     }
 
-    fn format_daily(&self, data: &Vec<DailyStats>, geo_title: &str) -> String {
+    // The headline numbers for a region, to go above its chart
+    fn format_daily(&self, data: &Vec<DailyStats>, geo_title: &str) -> Message {
         let mut total_positive = 0;
         let mut total_negative = 0;
         let mut total_hospitalized = 0;
@@ -403,183 +582,568 @@ impl Coronabot {
             None => {}
         }
 
-        let mut pos_change = 0.0;
-        let mut neg_change = 0.0;
-        let mut hosp_change = 0.0;
-        let mut tested_change = 0.0;
-        let mut dead_change = 0.0;
-
-        let yesterday_el = data.get(1);
-
-        match yesterday_el {
-            Some(el) => {
-                let yesterday_total_tested = el.positive.unwrap_or(0) + el.negative.unwrap_or(0);
-                pos_change = (((total_positive - el.positive.unwrap_or(0)) as f64 / el.positive.unwrap_or(0) as f64) * 100.0);
-                neg_change = (((total_negative - el.negative.unwrap_or(0)) as f64 / el.negative.unwrap_or(0) as f64) * 100.0);
-                hosp_change = (((total_hospitalized - el.hospitalized.unwrap_or(0)) as f64 / el.hospitalized.unwrap_or(0) as f64) * 100.0);
-                tested_change = (((total_tested - yesterday_total_tested) as f64 / yesterday_total_tested as f64) * 100.0);
-                dead_change = (((total_dead - el.death.unwrap_or(0)) as f64 / el.death.unwrap_or(0) as f64) * 100.0);
-            },
-            None => {}
+        let yesterday = data.get(1);
+        let change = |today: Option<u32>, field: fn(&DailyStats) -> Option<u32>| format_change(today, yesterday.and_then(field));
+        let pos_change = change(first_el.and_then(|el| el.positive), |el| el.positive);
+        let neg_change = change(first_el.and_then(|el| el.negative), |el| el.negative);
+        let hosp_change = change(first_el.and_then(|el| el.hospitalized), |el| el.hospitalized);
+        let tested_change = change(first_el.map(|_| total_tested), |el| Some(el.positive.unwrap_or(0) + el.negative.unwrap_or(0)));
+        let dead_change = change(first_el.and_then(|el| el.death), |el| el.death);
+
+        let fields = [
+            ("Total positive", format!("{total} ({change})", total=total_positive.to_formatted_string(&Locale::en), change=pos_change)),
+            ("Total negative", format!("{total} ({change})", total=total_negative.to_formatted_string(&Locale::en), change=neg_change)),
+            ("Total tested", format!("{total} ({change})", total=total_tested.to_formatted_string(&Locale::en), change=tested_change)),
+            ("Total hospitalized", format!("{total} ({change})", total=total_hospitalized.to_formatted_string(&Locale::en), change=hosp_change)),
+            ("Mortality rate", format!("{rate:.2}%", rate=death_rate)),
+            ("Souls lost", format!("{total} ({change})", total=total_dead.to_formatted_string(&Locale::en), change=dead_change)),
+        ];
+        let title = format!("{geo_title} Overall Daily Stats ({date})", geo_title=geo_title, date=date);
+        let mut text = title.clone();
+        for (label, value) in fields.iter() {
+            text.push_str(&format!("\n{label}: {value}", label=label, value=value));
         }
+        return Message::new(&text)
+            .header(&title)
+            .fields(&fields);
+    }
 
-        // If data is unreported or actually 0, % change will be undefined (0/0) but let's just say it's zero because it makes sense intuitively
-        if hosp_change.is_nan() {
-            hosp_change = 0.0;
-        }
-        if dead_change.is_nan() {
-            dead_change = 0.0;
-        }
-
-        return format!("
-        {geo_title} Overall Daily Stats ({date})\n \
-        Total positive: {total_positive} (+{pos_change:.0}%)\n \
-        Total negative: {total_negative} (+{neg_change:.0}%)\n \
-        Total tested: {total_tested} (+{tested_change:.0}%)\n \
-        Total hospitalized: {total_hospitalized} (+{hosp_change:.0}%)\n \
-        Mortality rate: {death_rate:.2}% \n \
-        Souls lost: {total_dead} (+{dead_change:.0}%)",
-                       geo_title=geo_title,
-                       date=date,
-                       death_rate=death_rate,
-                       total_positive=total_positive.to_formatted_string(&Locale::en),
-                       pos_change=pos_change,
-                       total_negative=total_negative.to_formatted_string(&Locale::en),
-                       neg_change=neg_change,
-                       total_hospitalized=total_hospitalized.to_formatted_string(&Locale::en),
-                       hosp_change=hosp_change,
-                       total_tested=total_tested.to_formatted_string(&Locale::en),
-                       tested_change=tested_change,
-                       total_dead=total_dead.to_formatted_string(&Locale::en),
-                       dead_change=dead_change);
-
-    }
-
-    fn handle_mention(&self, text: String, channel: String, cli: &RtmClient) {
-        let query_start = text.find(" ");
-        match query_start {
-            Some(q_string) => {
-
-                let spl: Vec<&str> = text.split_whitespace().collect();
-
-                if spl.len() > 1 && *spl.get(1).unwrap() == "help" {
-                    let to_send = "Usage:\n \
-                    Overall new positive cases: @coronabot latest\
-                    \nState new positive cases: @coronabot <state abbreviation>\
-                    \nCustom chart (beta): @coronabot custom <state abbreviation> y1 <expression>\
-                    \nCustom charts are aware of these variables: positive, negative, total, dead, hospitalized, pending. If you reference them in the expression, they will be interpolated into the expression. For example (positive/total) for infection rate.";
-                    cli.sender().send_message(&channel, &to_send);
-                    return;
-                }
-
-                if spl.len() > 3 && *spl.get(1).unwrap() == "custom" {
-                    let state = *spl.get(2).unwrap();
-                    let exp_start = text.find("y1");
+    pub fn identity(&self) -> &Identity {
+        return &self.identity;
+    }
 
-                    match exp_start {
-                        Some(_) => {},
-                        None => {
-                            let to_send = "Missing y-axis specifier. Usage: @coronabot custom <state> y1 <expression>";
-                            cli.sender().send_message(&channel, &to_send);
-                            return;
-                        }
-                    }
+    pub fn web(&self) -> &SlackWeb {
+        return &self.web;
+    }
 
-                    let exp = &text[exp_start.unwrap()+3..text.len()];
-                    println!("State: {:?} Exp: {:?}", state, exp);
+    // The most recent day in the data, None until it's first loaded
+    pub fn latest_date(&self) -> Option<NaiveDate> {
+        return *self.latest_date.read().unwrap();
+    }
 
-                    let state_stats = self.states_daily.read().unwrap();
-                    match &*state_stats {
-                        Some(data) => {
-                            if !data.contains_key(state) {
-                                let to_send = format!("State data is present but does not contain stats for {state}", state=state);
-                                cli.sender().send_message(&channel, &to_send);
+    // Runs a command, whether it came from a mention or a slash command
+    pub fn handle_command(&self, query: &str, reply: &Reply) {
+        let channel = reply.channel().to_string();
+        if query.is_empty() {
+            return;
+        }
+        let channel_spec = match self.store.read().unwrap().theme(&channel) {
+            Some(theme) => ChartSpec::with_theme(theme),
+            None => ChartSpec::default(),
+        };
+        let command = match command::parse(query, channel_spec) {
+            Ok(command) => command,
+            Err(err) => {
+                reply.send(&err);
+                return;
+            }
+        };
+        println!("Command: {:?}", command);
+        let actions = match self.has_controls() {
+            true => controls::actions_block(query, &command),
+            false => None,
+        };
+
+        match command {
+            Command::Help{topic} => {
+                let mut groups: Vec<&str> = self.config.groups.keys().map(|g| g.as_str()).collect();
+                groups.sort();
+                let to_send = command::help(topic.as_deref(), &groups);
+                reply.send(&to_send);
+            },
+            Command::Define{name, expression} => {
+                let to_send = self.define(&channel, &name, &expression);
+                reply.send(&to_send);
+            },
+            Command::Undefine{name} => {
+                let removed = self.store.write().unwrap().undefine(&channel, &name);
+                let to_send = match removed {
+                    true => format!("Removed {name}", name=name),
+                    false => format!("{name} isn't defined in this channel", name=name),
+                };
+                reply.send(&to_send);
+            },
+            Command::List => {
+                let to_send = self.list_definitions(&channel);
+                reply.send(&to_send);
+            },
+            Command::Theme{theme} => {
+                let to_send = match theme {
+                    Some(theme) => {
+                        self.store.write().unwrap().set_theme(&channel, theme);
+                        format!("Charts in this channel will use the {theme} theme", theme=theme.name())
+                    },
+                    None => {
+                        let theme = self.store.read().unwrap().theme(&channel).unwrap_or(Theme::Light);
+                        format!("This channel uses the {theme} theme. Usage: @coronabot theme <light|dark>", theme=theme.name())
+                    }
+                };
+                reply.send(&to_send);
+            },
+            Command::Top => {
+                let state_stats = self.states_daily.read().unwrap();
+                match &*state_stats {
+                    Some(data) => reply.send_message(&self.format_high_scores(data)),
+                    None => reply.send("Sorry, state-level data is missing. Is the API working?"),
+                }
+            },
+            Command::Latest{metric: Some(metric), range, spec} => {
+                self.handle_metric("latest", None, &metric, &range, &spec, actions, reply);
+            },
+            Command::Latest{metric: None, range, spec} => {
+                // Need to deref the rwlockguard, then borrow the option
+                let current_data = self.us_daily.read().unwrap();
+                match &*current_data {
+                    Some(data) => {
+                        let key = chart_cache::key("latest", &range, &spec);
+                        let summary = self.format_daily(data, "U.S.");
+                        let render = || self.generate_chart(self.new_cases_chart(data, "U.S. Coronavirus Cases".to_string(), &range), &spec);
+                        self.send_chart(&key, Some(summary), actions, render, reply);
+                    },
+                    None => {
+                        let to_send = "Sorry, country-level data is missing. Is the API working?";
+                        reply.send(&to_send);
+                    }
+                }
+            },
+            Command::State{region, compare, metric: Some(metric), range, spec} => {
+                self.handle_metric(&region, compare.as_deref(), &metric, &range, &spec, actions, reply);
+            },
+            Command::State{region, compare, metric: None, range, spec} => {
+                let state_stats = self.states_daily.read().unwrap();
+                match &*state_stats {
+                    Some(data) => {
+                        let (label, state_data) = match self.resolve_region(data, &region) {
+                            Ok(resolved) => resolved,
+                            Err(err) => {
+                                reply.send(&err);
                                 return;
                             }
-                            let state_data = data.get(state).unwrap();
-                            let chart_url = self.custom_chart(state_data, format!("{state} Custom Chart", state=state),exp.to_string());
-                            let mut to_send = String::new();
-                            to_send.push_str("\n");
-                            to_send.push_str(&chart_url);
-                            cli.sender().send_message(&channel, &to_send);
-                            return;
-                        },
-                        None => {
-                            let to_send = "Sorry, state-level data is missing. Is the API working?";
-                            cli.sender().send_message(&channel, &to_send);
-                            return;
+                        };
+                        let compare = match compare {
+                            Some(compare) => {
+                                match self.resolve_region(data, &compare) {
+                                    Ok(resolved) => Some(resolved),
+                                    Err(err) => {
+                                        reply.send(&err);
+                                        return;
+                                    }
+                                }
+                            },
+                            None => None,
+                        };
+                        match compare {
+                            Some((other_label, other_data)) => {
+                                let key = chart_cache::key(&format!("state {state} vs {other}", state=label, other=other_label), &range, &spec);
+                                let render = || {
+                                    let chart = self.new_cases_chart(&state_data, "".to_string(), &range);
+                                    let other = self.new_cases_chart(&other_data, "".to_string(), &range);
+                                    let title = titled_range(&format!("{state} vs {other} New Cases", state=label, other=other_label), &range);
+                                    self.generate_chart(compare_charts(title, chart, &label, other, &other_label), &comparison_spec(&spec))
+                                };
+                                self.send_chart(&key, None, actions, render, reply);
+                            },
+                            None => {
+                                let key = chart_cache::key(&format!("state {state}", state=label), &range, &spec);
+                                let summary = self.format_daily(&state_data, &label);
+                                let render = || self.generate_chart(self.new_cases_chart(&state_data, format!("{state} Coronavirus Cases", state=label), &range), &spec);
+                                self.send_chart(&key, Some(summary), actions, render, reply);
+                            }
                         }
+                    },
+                    None => {
+                        let to_send = "Sorry, state-level data is missing. Is the API working?";
+                        reply.send(&to_send);
                     }
                 }
-                println!("Splt: {:?}", spl);
-
-                let query = &text[q_string+1..text.len()];
-                println!("Got query: {:?}", query);
-                if query == "latest" {
-                    println!("Getting current data");
-                    let current_data = self.us_daily.read().unwrap();
-                    println!("Got data");
-
-                    // Need to deref the rwlockguard, then borrow the option
-                    // TODO: pretty ugly, is there a nicer way to do this?
-                    match &*current_data {
-                        Some(data) => {
-                            println!("Getting data");
-                            let mut to_send = "".to_string();
-                            let chart_url = self.generate_new_cases_chart(data, "U.S. Coronavirus Cases".to_string());
-                            to_send.push_str("\n");
-                            to_send.push_str(&chart_url);
-                            println!("Sending data");
-                            cli.sender().send_message(&channel, &to_send);
-                        },
-                        None => {
-                            let to_send = "Sorry, country-level data is missing. Is the API working?";
-                            cli.sender().send_message(&channel, &to_send);
-                        }
-                    }
-                } else if query == "top" {
-                    let state_stats = self.states_daily.read().unwrap();
-                    match &*state_stats {
-                        Some(data) => {
-                            let to_send = self.format_high_scores(data);
-                            cli.sender().send_message(&channel, &to_send);
-                        },
-                        None => {
-                            let to_send = "Sorry, state-level data is missing. Is the API working?";
-                            cli.sender().send_message(&channel, &to_send);
-                        }
+            },
+            Command::Custom{region, expression, range, spec} => {
+                let exp = match self.expand_expression(&channel, &expression) {
+                    Ok(exp) => exp,
+                    Err(err) => {
+                        reply.send(&err);
+                        return;
                     }
-                } else {
-                    let state_stats = self.states_daily.read().unwrap();
-                    match &*state_stats {
-                        Some(data) => {
-                            if !data.contains_key(query) {
-                                let to_send = format!("State data is present but does not contain stats for {state}", state=query);
-                                cli.sender().send_message(&channel, &to_send);
+                };
+
+                let state_stats = self.states_daily.read().unwrap();
+                match &*state_stats {
+                    Some(data) => {
+                        let (label, state_data) = match self.resolve_region(data, &region) {
+                            Ok(resolved) => resolved,
+                            Err(err) => {
+                                reply.send(&err);
                                 return;
                             }
-                            let state_data = data.get(query).unwrap();
-                            let chart_url = self.generate_new_cases_chart(state_data, format!("{state} Coronavirus Cases", state=query));
-                            let mut to_send = "".to_string();
-                            to_send.push_str("\n");
-                            to_send.push_str(&chart_url);
-                            cli.sender().send_message(&channel, &to_send);
-                        },
-                        None => {
-                            let to_send = "Sorry, state-level data is missing. Is the API working?";
-                            cli.sender().send_message(&channel, &to_send);
-                        }
+                        };
+                        let key = chart_cache::key(&format!("custom {state} {exp}", state=label, exp=exp), &range, &spec);
+                        let render = || self.generate_chart(self.custom_chart(&state_data, format!("{state} Custom Chart", state=label), exp, &range)?, &spec);
+                        self.send_chart(&key, None, actions, render, reply);
+                    },
+                    None => {
+                        let to_send = "Sorry, state-level data is missing. Is the API working?";
+                        reply.send(&to_send);
                     }
                 }
             },
-            None => {
+            Command::Subscribe{query, schedule, timezone, description} => {
+                let to_send = self.subscribe(&channel, query, schedule, timezone, description);
+                reply.send(&to_send);
+            },
+            Command::Unsubscribe{which} => {
+                let to_send = self.unsubscribe(&channel, &which);
+                reply.send(&to_send);
+            },
+            Command::Subscriptions => {
+                let to_send = self.list_subscriptions(&channel);
+                reply.send(&to_send);
+            },
+            Command::Alert{region, metric, condition} => {
+                let to_send = self.add_alert(&channel, region, metric, condition);
+                reply.send(&to_send);
+            },
+            Command::Unalert{which} => {
+                let to_send = self.remove_alerts(&channel, &which);
+                reply.send(&to_send);
+            },
+            Command::Alerts => {
+                let to_send = self.list_alerts(&channel);
+                reply.send(&to_send);
+            },
+        }
+    }
+
+    fn add_alert(&self, channel: &str, region: String, metric: String, condition: Condition) -> String {
+        if self.store.read().unwrap().alerts(channel).len() >= MAX_ALERTS {
+            return format!("This channel already has {max} alerts, remove one first", max=MAX_ALERTS);
+        }
+        let definitions = self.store.read().unwrap().definitions(channel);
+        if !definitions.contains_key(&metric) && !expressions::VARIABLES.contains(&metric.as_str()) {
+            return format!("{metric} isn't defined in this channel. Usage: @coronabot define <name> = <expression>", metric=metric);
+        }
+        let alert = Alert{id: Uuid::new_v4().to_string(), region: region, metric: metric, condition: condition, triggered: false};
+        // Catch a bad region now, rather than every time the alert is checked
+        match &*self.states_daily.read().unwrap() {
+            Some(data) => {
+                match self.alert_values(data, channel, &alert) {
+                    Ok(_) => {},
+                    Err(err) => return err,
+                }
+            },
+            None => {}
+        }
+        let to_send = format!("I'll post here when {alert}, checked whenever new data comes in", alert=alert.describe());
+        self.store.write().unwrap().add_alert(channel, alert);
+        return to_send;
+    }
+
+    fn remove_alerts(&self, channel: &str, which: &str) -> String {
+        let alerts = self.store.read().unwrap().alerts(channel);
+        let matching: Vec<&Alert> = if which.to_lowercase() == "all" {
+            alerts.iter().collect()
+        } else {
+            match which.parse::<usize>() {
+                Ok(number) if number >= 1 && number <= alerts.len() => vec![&alerts[number - 1]],
+                _ => Vec::new(),
+            }
+        };
+        if matching.is_empty() {
+            return format!("No alert here matches {which}, see @coronabot alerts", which=which);
+        }
+        let ids: Vec<String> = matching.iter().map(|a| a.id.clone()).collect();
+        let removed: Vec<String> = matching.iter().map(|a| a.describe()).collect();
+        self.store.write().unwrap().remove_alerts(channel, &ids);
+        return format!("Removed alerts for {removed}", removed=removed.join(", "));
+    }
+
+    fn list_alerts(&self, channel: &str) -> String {
+        let alerts = self.store.read().unwrap().alerts(channel);
+        if alerts.is_empty() {
+            return "There are no alerts here. Usage: @coronabot alert <state> <metric> <condition>".to_string();
+        }
+        let mut to_send = "Alerts:".to_string();
+        for (i, alert) in alerts.iter().enumerate() {
+            let status = if alert.triggered { " (currently met)" } else { "" };
+            to_send.push_str(&format!("\n{number}. {alert}{status}", number=i + 1, alert=alert.describe(), status=status));
+        }
+        return to_send;
+    }
+
+    // The alert's metric for each day in its region, oldest first
+    fn alert_values(&self, data: &HashMap<String, Vec<DailyStats>>, channel: &str, alert: &Alert) -> Result<Vec<f32>, String> {
+        let exp = self.expand_expression(channel, &alert.metric)?;
+        let (label, state_data) = self.resolve_region(data, &alert.region)?;
+        let chart = self.custom_chart(&state_data, label, exp, &DateRange::All)?;
+        return Ok(chart.y1);
+    }
+
+    // Posts the alerts whose conditions have started to hold since they were last checked, and
+    // rearms the ones whose conditions no longer do
+    fn check_alerts(&self) {
+        let alerts = self.store.read().unwrap().all_alerts();
+        // Check everything first and send after, so the data isn't locked against the next
+        // refresh while talking to Slack
+        let mut checked = Vec::new();
+        {
+            let state_stats = self.states_daily.read().unwrap();
+            let data = match &*state_stats {
+                Some(data) => data,
+                None => return,
+            };
+            for (channel, alert) in alerts.iter() {
+                match self.alert_values(data, channel, alert) {
+                    Ok(values) => checked.push((channel, alert, alert.condition.check(&values))),
+                    Err(err) => println!("Failed to check alert {:} in {:}: {:}", alert.describe(), channel, err),
+                }
+            }
+        }
+
+        for (channel, alert, reason) in checked {
+            match (reason, alert.triggered) {
+                (Some(reason), false) => {
+                    println!("Alert {:} in {:} went off", alert.describe(), channel);
+                    let to_send = format!("Alert: {alert}, {reason}", alert=alert.describe(), reason=reason);
+                    Reply::new(&self.web, channel, None).send_message(&Message::new(&to_send).section(&to_send).context(&self.data_footer()));
+                    self.store.write().unwrap().set_alert_triggered(channel, &alert.id, true);
+                },
+                (None, true) => {
+                    self.store.write().unwrap().set_alert_triggered(channel, &alert.id, false);
+                },
+                _ => {}
+            }
+        }
+    }
+
+    // Controls under charts need an interactions endpoint to send clicks to, which RTM doesn't have,
+    // and a chart URL to replace the message with
+    fn has_controls(&self) -> bool {
+        match self.config.transport {
+            TransportConfig::Rtm => return false,
+            _ => return self.image_store.gives_urls(),
+        }
+    }
+
+    // Every channel's subscriptions, for the scheduler to send
+    pub fn subscriptions(&self) -> Vec<(String, Subscription)> {
+        return self.store.read().unwrap().all_subscriptions();
+    }
+
+    // The day of data a briefing last went out with, None if it never has
+    pub fn briefed(&self, key: &str) -> Option<NaiveDate> {
+        return self.store.read().unwrap().briefed(key);
+    }
+
+    pub fn set_briefed(&self, key: &str, data_date: NaiveDate) {
+        self.store.write().unwrap().set_briefed(key, data_date);
+    }
+
+    fn subscribe(&self, channel: &str, query: String, schedule: String, timezone: Option<String>, description: String) -> String {
+        if self.store.read().unwrap().subscriptions(channel).len() >= MAX_SUBSCRIPTIONS {
+            return format!("This channel already has {max} subscriptions, unsubscribe from one first", max=MAX_SUBSCRIPTIONS);
+        }
+        let subscription = Subscription{
+            id: Uuid::new_v4().to_string(),
+            query: query,
+            schedule: schedule,
+            timezone: timezone.unwrap_or(self.config.timezone.clone()),
+            description: description,
+        };
+        let to_send = format!("Subscribed to {query} {description} ({timezone}), once there's new data. @coronabot subscriptions lists them.",
+                              query=subscription.query, description=subscription.description, timezone=subscription.timezone);
+        self.store.write().unwrap().subscribe(channel, subscription);
+        return to_send;
+    }
+
+    fn unsubscribe(&self, channel: &str, which: &str) -> String {
+        let subscriptions = self.store.read().unwrap().subscriptions(channel);
+        let matching: Vec<&Subscription> = if which.to_lowercase() == "all" {
+            subscriptions.iter().collect()
+        } else {
+            match which.parse::<usize>() {
+                Ok(number) if number >= 1 && number <= subscriptions.len() => vec![&subscriptions[number - 1]],
+                Ok(_) => Vec::new(),
+                Err(_) => subscriptions.iter().filter(|s| s.query.to_lowercase() == which.to_lowercase()).collect(),
             }
+        };
+        if matching.is_empty() {
+            return format!("No subscription here matches {which}, see @coronabot subscriptions", which=which);
         }
+        let ids: Vec<String> = matching.iter().map(|s| s.id.clone()).collect();
+        let removed: Vec<String> = matching.iter().map(|s| format!("{query} {description}", query=s.query, description=s.description)).collect();
+        self.store.write().unwrap().unsubscribe(channel, &ids);
+        return format!("Unsubscribed from {removed}", removed=removed.join(", "));
     }
 
-    pub fn start_bg_update(&self) {
+    fn list_subscriptions(&self, channel: &str) -> String {
+        let subscriptions = self.store.read().unwrap().subscriptions(channel);
+        if subscriptions.is_empty() {
+            return "There are no subscriptions here. Usage: @coronabot subscribe <report> [daily|weekdays|<day>] [at] <time> [timezone]".to_string();
+        }
+        let mut to_send = "Subscriptions:".to_string();
+        for (i, subscription) in subscriptions.iter().enumerate() {
+            to_send.push_str(&format!("\n{number}. {query} {description} ({timezone})",
+                                      number=i + 1, query=subscription.query, description=subscription.description, timezone=subscription.timezone));
+        }
+        return to_send;
+    }
+
+    fn list_definitions(&self, channel: &str) -> String {
+        let definitions = self.store.read().unwrap().definitions(channel);
+        if definitions.is_empty() {
+            return "No expressions are defined in this channel. Usage: @coronabot define <name> = <expression>".to_string();
+        }
+        let mut names: Vec<&String> = definitions.keys().collect();
+        names.sort();
+        let mut to_send = "Defined expressions:".to_string();
+        for name in names {
+            to_send.push_str(&format!("\n{name} = {expression}", name=name, expression=definitions.get(name).unwrap()));
+        }
+        return to_send;
+    }
+
+    fn define(&self, channel: &str, name: &str, expression: &str) -> String {
+        let usage = "Usage: @coronabot define <name> = <expression>";
+        if !expressions::is_valid_name(name) {
+            return format!("{name} isn't a valid name, use letters, numbers and underscores. {usage}", name=name, usage=usage);
+        }
+        if expressions::is_reserved(name) {
+            return format!("{name} is a built-in variable or function and can't be redefined", name=name);
+        }
+        // Expand against the definitions we'd have after saving so self-references are caught now
+        let mut definitions = self.store.read().unwrap().definitions(channel);
+        definitions.insert(name.to_string(), expression.to_string());
+        let expanded = match expressions::expand(expression, &definitions) {
+            Ok(expanded) => expanded,
+            Err(err) => return err,
+        };
+        match expressions::validate(&expanded) {
+            Ok(()) => {},
+            Err(err) => return err,
+        }
+
+        self.store.write().unwrap().define(channel, name, expression);
+        return format!("Defined {name} = {expression}", name=name, expression=expression);
+    }
+
+    // Turns a region like "CA", "california+or+WA" or a configured group like "west" into a single
+    // series, summing the underlying counts across states when there's more than one. Also returns
+    // a tidied up name for the region to use in titles.
+    fn resolve_region(&self, data: &HashMap<String, Vec<DailyStats>>, region: &str) -> Result<(String, Vec<DailyStats>), String> {
+        let mut states = Vec::new();
+        let mut labels = Vec::new();
+        for part in region.split("+") {
+            let group_name = part.trim().to_lowercase();
+            match self.config.groups.get(&group_name) {
+                Some(group) => {
+                    states.extend(group.iter().cloned());
+                    labels.push(group_name);
+                },
+                None => {
+                    let state = regions::resolve(part)?;
+                    states.push(state.to_string());
+                    labels.push(state.to_string());
+                }
+            }
+        }
+        states.sort();
+        states.dedup();
+        let label = labels.join("+");
+
+        let mut series = Vec::new();
+        for state in states.iter() {
+            match data.get(state) {
+                Some(state_data) => series.push(state_data),
+                None => return Err(format!("State data is present but does not contain stats for {state}", state=state)),
+            }
+        }
+        if series.len() == 1 {
+            return Ok((label, series[0].clone()));
+        }
+        let aggregate = aggregate_states(&series);
+        if aggregate.is_empty() {
+            return Err(format!("There are no days that every state in {label} reported", label=label));
+        }
+        return Ok((label, aggregate));
+    }
+
+    fn expand_expression(&self, channel: &str, expression: &str) -> Result<String, String> {
+        let definitions = self.store.read().unwrap().definitions(channel);
+        return expressions::expand(expression, &definitions);
+    }
+
+    fn handle_metric(&self, region: &str, compare: Option<&str>, metric: &str, range: &DateRange, spec: &ChartSpec, actions: Option<Value>, reply: &Reply) {
+        let definitions = self.store.read().unwrap().definitions(reply.channel());
+        if !definitions.contains_key(metric) && !expressions::VARIABLES.contains(&metric) {
+            let to_send = format!("{metric} isn't defined in this channel. Usage: @coronabot define <name> = <expression>", metric=metric);
+            reply.send(&to_send);
+            return;
+        }
+        let expression = match expressions::expand(metric, &definitions) {
+            Ok(expression) => expression,
+            Err(err) => {
+                reply.send(&err);
+                return;
+            }
+        };
+
+        if region == "latest" {
+            let key = chart_cache::key(&format!("metric latest {metric} {expression}", metric=metric, expression=expression), range, spec);
+            let render_chart = || {
+                let current_data = self.us_daily.read().unwrap();
+                match &*current_data {
+                    Some(data) => self.generate_chart(self.custom_chart(data, format!("U.S. {metric}", metric=metric), expression, range)?, spec),
+                    None => Err("Sorry, country-level data is missing. Is the API working?".to_string()),
+                }
+            };
+            self.send_chart(&key, None, actions, render_chart, reply);
+            return;
+        }
+
+        // Resolve the regions before looking in the cache so "ca", "CA" and "California" share a chart
+        let resolved = {
+            let state_stats = self.states_daily.read().unwrap();
+            match &*state_stats {
+                Some(data) => self.resolve_region(data, region).and_then(|resolved| {
+                    match compare {
+                        Some(compare) => Ok((resolved, Some(self.resolve_region(data, compare)?))),
+                        None => Ok((resolved, None)),
+                    }
+                }),
+                None => Err("Sorry, state-level data is missing. Is the API working?".to_string()),
+            }
+        };
+        let ((label, state_data), other) = match resolved {
+            Ok(resolved) => resolved,
+            Err(err) => {
+                reply.send(&err);
+                return;
+            }
+        };
+
+        let other_label = other.as_ref().map(|(other_label, _)| other_label.as_str()).unwrap_or("");
+        let key = chart_cache::key(&format!("metric {state} vs {other} {metric} {expression}", state=label, other=other_label, metric=metric, expression=expression), range, spec);
+        let render_chart = || {
+            let chart = self.custom_chart(&state_data, format!("{state} {metric}", state=label, metric=metric), expression.clone(), range)?;
+            match &other {
+                Some((other_label, other_data)) => {
+                    let other = self.custom_chart(other_data, "".to_string(), expression, range)?;
+                    let title = titled_range(&format!("{state} vs {other} {metric}", state=label, other=other_label, metric=metric), range);
+                    self.generate_chart(compare_charts(title, chart, &label, other, other_label), &comparison_spec(spec))
+                },
+                None => self.generate_chart(chart, spec),
+            }
+        };
+        self.send_chart(&key, None, actions, render_chart, reply);
+    }
+
+    pub fn start_bg_update(self: &Arc<Coronabot>) {
+        let my_bot = self.clone();
         let my_us_daily = self.us_daily.clone();
         let my_states_daily = self.states_daily.clone();
+        let my_data_version = self.data_version.clone();
+        let my_latest_date = self.latest_date.clone();
+        let my_chart_cache = self.chart_cache.clone();
+        let my_image_store = self.image_store.clone();
         thread::spawn(move || {
             loop {
                 println!("Making US daily query...");
@@ -588,6 +1152,9 @@ impl Coronabot {
                     .text()
                     .unwrap();
                 let parsed: Vec<DailyStats> = serde_json::from_str(&body).unwrap();
+                let latest_date = parsed.first()
+                    .and_then(|el| el.date)
+                    .and_then(|date| NaiveDate::parse_from_str(&date.to_string(), "%Y%m%d").ok());
                 let mut data = my_us_daily
                     .write()
                     .unwrap();
@@ -608,6 +1175,21 @@ impl Coronabot {
                     .unwrap();
                 *states_data = Some(states_map);
                 drop(states_data);
+                // Only once both are in, so briefings waiting on it see the states' new data too
+                *my_latest_date.write().unwrap() = latest_date;
+
+                // Charts rendered from the old data won't be served again, delete the ones nobody
+                // is likely to still be looking at
+                let version = my_data_version.fetch_add(1, Ordering::SeqCst) + 1;
+                let expired = my_chart_cache.write().unwrap().expire(version);
+                for name in expired {
+                    match my_image_store.delete(&name) {
+                        Ok(()) => println!("Deleted stale chart {:}", name),
+                        Err(err) => println!("Failed to delete stale chart {:}: {:}", name, err),
+                    }
+                }
+
+                my_bot.check_alerts();
 
                 // Rerun once an hour
                 thread::sleep(Duration::from_millis(1000 * 60 * 60));
@@ -616,33 +1198,62 @@ impl Coronabot {
     }
 }
 
-#[allow(unused_variables)]
-impl slack::EventHandler for Coronabot {
-    fn on_event(&mut self, cli: &RtmClient, event: Event) {
-        match event {
-            Event::Message(msg) => {
-                match *msg {
-                    Message::Standard(msg) => {
-                        println!("msg: {:?}", msg);
-                        let text = msg.text.unwrap();
-                        println!("text: {:?}", text);
-                        if text.contains(&self.bot_id) {
-                            println!("Mentioned");
-                            let channel = msg.channel.unwrap();
-                            self.handle_mention(text, channel, cli);
-                        }
-                    },
-                    _ => {}
-                }
-            },
-            _ => {}
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(state: &str, date: u32, positive: Option<u32>, death: Option<u32>) -> DailyStats {
+        return DailyStats{state: Some(state.to_string()), date: Some(date), positive: positive, negative: None,
+                          pending: None, hospitalized: None, death: death, total: None};
     }
-    fn on_close(&mut self, cli: &RtmClient) {
-        println!("Connection closed");
+
+    #[test]
+    fn aggregates_states() {
+        let ca = vec![day("CA", 20200602, Some(20), Some(2)), day("CA", 20200601, Some(10), Some(1))];
+        let or = vec![day("OR", 20200602, Some(5), None), day("OR", 20200601, Some(3), None)];
+        let sum = aggregate_states(&vec![&ca, &or]);
+        assert_eq!(sum.len(), 2);
+        assert_eq!(sum[0].date, Some(20200602));
+        assert_eq!(sum[0].state, None);
+        assert_eq!(sum[0].positive, Some(25));
+        assert_eq!(sum[0].death, Some(2));
+        assert_eq!(sum[1].positive, Some(13));
+        // Nobody reported it, so it stays missing rather than becoming 0
+        assert_eq!(sum[1].hospitalized, None);
     }
 
-    fn on_connect(&mut self, cli: &RtmClient) {
-        println!("Coronabot connected");
+    #[test]
+    fn aggregates_only_common_dates() {
+        let ca = vec![day("CA", 20200603, Some(30), None), day("CA", 20200602, Some(20), None), day("CA", 20200601, Some(10), None)];
+        let or = vec![day("OR", 20200602, Some(5), None), day("OR", 20200601, Some(3), None)];
+        let wa = vec![day("WA", 20200603, Some(7), None), day("WA", 20200602, Some(6), None)];
+        let sum = aggregate_states(&vec![&ca, &or, &wa]);
+        assert_eq!(sum.iter().map(|dp| dp.date.unwrap()).collect::<Vec<u32>>(), vec![20200602]);
+        assert_eq!(sum[0].positive, Some(31));
+
+        let ny = vec![day("NY", 20200604, Some(1), None)];
+        assert!(aggregate_states(&vec![&ca, &ny]).is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn changes() {
+        assert_eq!(format_change(Some(105), Some(100)), "+5%");
+        assert_eq!(format_change(Some(100), Some(100)), "+0%");
+        assert_eq!(format_change(Some(u32::MAX), Some(1)), "+429496729400%");
+    }
+
+    #[test]
+    fn falling_counts() {
+        // Cumulative counts get revised down now and then
+        assert_eq!(format_change(Some(90), Some(100)), "-10%");
+        assert_eq!(format_change(Some(0), Some(4000000000)), "-100%");
+    }
+
+    #[test]
+    fn nothing_to_compare_with() {
+        assert_eq!(format_change(Some(10), Some(0)), "n/a");
+        assert_eq!(format_change(Some(10), None), "n/a");
+        // e.g. hospitalized, which often isn't reported for the latest day
+        assert_eq!(format_change(None, Some(10)), "n/a");
+    }
+}
@@ -0,0 +1,185 @@
+use chrono::{Duration, NaiveDate};
+
+// About ten years, far longer than any of the data goes back
+const MAX_DAYS: i64 = 3650;
+
+// Which part of a time series a chart should show. Relative ranges are measured back from
+// the newest data point rather than today, since the data usually lags by a day.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DateRange {
+    All,
+    Last(Duration),
+    Since(NaiveDate),
+    Between(NaiveDate, NaiveDate),
+}
+
+impl DateRange {
+    pub fn contains(&self, date: NaiveDate, latest: NaiveDate) -> bool {
+        match self {
+            DateRange::All => true,
+            DateRange::Last(duration) => match latest.checked_sub_signed(*duration) {
+                Some(start) => date > start,
+                None => true,
+            },
+            DateRange::Since(start) => date >= *start,
+            DateRange::Between(start, end) => date >= *start && date <= *end,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            DateRange::All => "".to_string(),
+            DateRange::Last(duration) => format!("last {:} days", duration.num_days()),
+            DateRange::Since(start) => format!("since {:}", start),
+            DateRange::Between(start, end) => format!("{:} to {:}", start, end),
+        }
+    }
+
+    // The range as it would be typed in a command, so extract gives it back
+    pub fn to_words(&self) -> String {
+        match self {
+            DateRange::All => "".to_string(),
+            DateRange::Last(duration) => format!("last {:}d", duration.num_days()),
+            DateRange::Since(start) => format!("since {:}", start.format("%Y-%m-%d")),
+            DateRange::Between(start, end) => format!("from {:} to {:}", start.format("%Y-%m-%d"), end.format("%Y-%m-%d")),
+        }
+    }
+}
+
+// Pulls a date range out of the words of a command, returning the command with the range removed.
+// Understands "last 30d" (also w for weeks, m for 30-day months), "since 2020-06-01" and
+// "from 2020-04-01 to 2020-06-01".
+pub fn extract(text: &str) -> Result<(String, DateRange), String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let mut remaining = Vec::new();
+    let mut range = DateRange::All;
+    let mut i = 0;
+    while i < words.len() {
+        let word = words[i].to_lowercase();
+        let next = words.get(i + 1);
+        if word == "last" && next.is_some() {
+            match parse_duration(next.unwrap())? {
+                Some(duration) => {
+                    range = DateRange::Last(duration);
+                    i += 2;
+                    continue;
+                },
+                None => {}
+            }
+        } else if word == "since" && next.is_some() {
+            let start = parse_date(next.unwrap())?;
+            range = DateRange::Since(start);
+            i += 2;
+            continue;
+        } else if word == "from" && next.is_some() {
+            let start = parse_date(next.unwrap())?;
+            let to = words.get(i + 2).map(|w| w.to_lowercase());
+            if to != Some("to".to_string()) || words.get(i + 3).is_none() {
+                return Err("Missing end of date range. Usage: from <YYYY-MM-DD> to <YYYY-MM-DD>".to_string());
+            }
+            let end = parse_date(words[i + 3])?;
+            if end < start {
+                return Err(format!("Date range ends ({:}) before it starts ({:})", end, start));
+            }
+            range = DateRange::Between(start, end);
+            i += 4;
+            continue;
+        }
+        remaining.push(words[i]);
+        i += 1;
+    }
+    return Ok((remaining.join(" "), range));
+}
+
+// None when word isn't a duration at all, so "last" can be left as an ordinary word
+fn parse_duration(word: &str) -> Result<Option<Duration>, String> {
+    let word = word.to_lowercase();
+    let (num, days_per_unit) = if word.ends_with("d") {
+        (&word[..word.len() - 1], 1)
+    } else if word.ends_with("w") {
+        (&word[..word.len() - 1], 7)
+    } else if word.ends_with("m") {
+        (&word[..word.len() - 1], 30)
+    } else {
+        (&word[..], 1)
+    };
+    match num.parse::<i64>() {
+        Ok(n) if n > 0 && n <= MAX_DAYS / days_per_unit => Ok(Some(Duration::days(n * days_per_unit))),
+        Ok(n) if n > 0 => Err(format!("last {word} is too long, the most is {max}d", word=word, max=MAX_DAYS)),
+        Err(_) if num.chars().all(|c| c.is_ascii_digit()) && !num.is_empty() => {
+            Err(format!("last {word} is too long, the most is {max}d", word=word, max=MAX_DAYS))
+        },
+        _ => Ok(None),
+    }
+}
+
+fn parse_date(word: &str) -> Result<NaiveDate, String> {
+    match NaiveDate::parse_from_str(word, "%Y-%m-%d") {
+        Ok(date) => Ok(date),
+        Err(_) => Err(format!("Couldn't read date {:}, use YYYY-MM-DD", word)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        return NaiveDate::from_ymd(year, month, day);
+    }
+
+    #[test]
+    fn extract_last() {
+        assert_eq!(extract("CA last 30d positive"), Ok(("CA positive".to_string(), DateRange::Last(Duration::days(30)))));
+        assert_eq!(extract("CA last 2W"), Ok(("CA".to_string(), DateRange::Last(Duration::days(14)))));
+        assert_eq!(extract("CA last 3m"), Ok(("CA".to_string(), DateRange::Last(Duration::days(90)))));
+        assert_eq!(extract("CA last 10"), Ok(("CA".to_string(), DateRange::Last(Duration::days(10)))));
+        // Not a duration, so it's left for the command
+        assert_eq!(extract("last week"), Ok(("last week".to_string(), DateRange::All)));
+        assert_eq!(extract("CA last"), Ok(("CA last".to_string(), DateRange::All)));
+        assert_eq!(extract("CA last 0d"), Ok(("CA last 0d".to_string(), DateRange::All)));
+    }
+
+    #[test]
+    fn extract_last_too_long() {
+        assert_eq!(extract("CA last 3650d").map(|(_, range)| range), Ok(DateRange::Last(Duration::days(MAX_DAYS))));
+        assert!(extract("CA last 3651d").is_err());
+        assert!(extract("CA last 1000m").is_err());
+        assert!(extract("CA last 99999999999999999999d").is_err());
+    }
+
+    #[test]
+    fn extract_since_and_between() {
+        assert_eq!(extract("since 2020-06-01 CA"), Ok(("CA".to_string(), DateRange::Since(date(2020, 6, 1)))));
+        assert_eq!(extract("CA from 2020-04-01 TO 2020-06-01"),
+                   Ok(("CA".to_string(), DateRange::Between(date(2020, 4, 1), date(2020, 6, 1)))));
+        assert!(extract("CA since yesterday").is_err());
+        assert!(extract("CA from 2020-04-01").is_err());
+        assert!(extract("CA from 2020-04-01 until 2020-06-01").is_err());
+        assert!(extract("CA from 2020-06-01 to 2020-04-01").is_err());
+        assert!(extract("CA from 2020-04-01 to 2020-13-01").is_err());
+    }
+
+    #[test]
+    fn round_trips_through_words() {
+        let ranges = [
+            DateRange::Last(Duration::days(30)),
+            DateRange::Since(date(2020, 6, 1)),
+            DateRange::Between(date(2020, 4, 1), date(2020, 6, 1)),
+        ];
+        for range in ranges.iter() {
+            assert_eq!(extract(&range.to_words()), Ok(("".to_string(), range.clone())));
+        }
+    }
+
+    #[test]
+    fn contains() {
+        let latest = date(2020, 6, 30);
+        let last_week = DateRange::Last(Duration::days(7));
+        assert!(last_week.contains(date(2020, 6, 24), latest));
+        assert!(!last_week.contains(date(2020, 6, 23), latest));
+        assert!(DateRange::Last(Duration::max_value()).contains(date(2020, 1, 1), latest));
+        assert!(DateRange::Between(date(2020, 4, 1), date(2020, 6, 1)).contains(date(2020, 6, 1), latest));
+        assert!(!DateRange::Since(date(2020, 6, 1)).contains(date(2020, 5, 31), latest));
+    }
+}
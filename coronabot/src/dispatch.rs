@@ -0,0 +1,272 @@
+use crate::blocks::Message;
+use crate::command;
+use crate::controls;
+use crate::coronabot::Coronabot;
+use crate::reply::Reply;
+use crate::worker_pool::{Job, WorkerPool};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+// How many answered messages to remember, so that editing one of them updates its answer
+const MAX_ANSWERED: usize = 500;
+
+// A message from Slack, whichever way it arrived
+#[derive(Debug, Clone)]
+pub struct IncomingMessage {
+    pub channel: String,
+    pub text: String,
+    pub ts: String,
+    // Set when the message is a reply in a thread
+    pub thread_ts: Option<String>,
+    pub user: Option<String>,
+    // Set when a bot (or app) posted the message
+    pub bot_id: Option<String>,
+    // In a DM with us, where every message is meant for the bot
+    pub is_direct: bool,
+    // An edit of a message sent earlier, which has the same ts
+    pub edited: bool,
+}
+
+impl IncomingMessage {
+    // Reads a message out of an Events API event (the "event" object of an event_callback), which
+    // is what both the HTTP endpoint and Socket Mode deliver. Edits come as message events
+    // (message.channels and friends for channels, message.im for DMs), anything else that isn't a
+    // plain message from someone is ignored.
+    pub fn from_event(event: &Value) -> Option<IncomingMessage> {
+        let is_direct = event["channel_type"].as_str() == Some("im");
+        match (event["type"].as_str(), event["subtype"].as_str()) {
+            // Mentions in channels
+            (Some("app_mention"), _) => {},
+            (Some("message"), Some("message_changed")) => {
+                let message = &event["message"];
+                // Also sent when e.g. a thread reply or link preview is added, which isn't an edit
+                if message["text"] == event["previous_message"]["text"] {
+                    return None;
+                }
+                let mut edited = IncomingMessage::from_message(event["channel"].as_str()?, message, is_direct)?;
+                edited.edited = true;
+                return Some(edited);
+            },
+            // Messages in DMs, which don't come as app_mention. Messages anywhere else would double
+            // up with it.
+            (Some("message"), None) | (Some("message"), Some("file_share")) | (Some("message"), Some("thread_broadcast")) if is_direct => {},
+            _ => return None,
+        }
+        return IncomingMessage::from_message(event["channel"].as_str()?, event, is_direct);
+    }
+
+    fn from_message(channel: &str, message: &Value, is_direct: bool) -> Option<IncomingMessage> {
+        return Some(IncomingMessage{
+            channel: channel.to_string(),
+            text: message["text"].as_str()?.to_string(),
+            ts: message["ts"].as_str()?.to_string(),
+            thread_ts: message["thread_ts"].as_str().map(|ts| ts.to_string()),
+            user: message["user"].as_str().map(|user| user.to_string()),
+            bot_id: message["bot_id"].as_str().map(|id| id.to_string()),
+            is_direct: is_direct,
+            edited: false,
+        });
+    }
+}
+
+// A slash command invocation, e.g. "/covid CA last 30d"
+#[derive(Debug, Clone)]
+pub struct SlashCommand {
+    pub channel: String,
+    pub text: String,
+    pub response_url: String,
+}
+
+impl SlashCommand {
+    // Reads the fields Slack sends with a slash command, form encoded over HTTP or as a JSON object
+    // over Socket Mode
+    pub fn from_fields(fields: &HashMap<String, String>) -> Option<SlashCommand> {
+        return Some(SlashCommand{
+            channel: fields.get("channel_id")?.to_string(),
+            text: fields.get("text").map(|text| text.trim().to_string()).unwrap_or_default(),
+            response_url: fields.get("response_url")?.to_string(),
+        });
+    }
+}
+
+// A button or menu used on one of our messages
+#[derive(Debug, Clone)]
+pub struct Interaction {
+    pub channel: String,
+    // The command that made the message, and what to change about it
+    pub query: String,
+    pub action: String,
+    pub response_url: String,
+}
+
+impl Interaction {
+    // Reads a block_actions payload, which arrives the same way over HTTP and Socket Mode. Anything
+    // that isn't one of the chart controls is ignored.
+    pub fn from_payload(payload: &Value) -> Option<Interaction> {
+        if payload["type"].as_str() != Some("block_actions") {
+            return None;
+        }
+        let action = &payload["actions"][0];
+        // Buttons have a value, menus the value of whatever was picked
+        let value = match action["value"].as_str() {
+            Some(value) => value,
+            None => action["selected_option"]["value"].as_str()?,
+        };
+        return Some(Interaction{
+            channel: payload["channel"]["id"].as_str()?.to_string(),
+            query: controls::query_from_block_id(action["block_id"].as_str()?)?.to_string(),
+            action: value.to_string(),
+            response_url: payload["response_url"].as_str()?.to_string(),
+        });
+    }
+}
+
+// The replies to the most recent messages we answered, by channel and message ts
+struct Answered {
+    replies: HashMap<String, Reply>,
+    order: VecDeque<String>,
+}
+
+impl Answered {
+    fn get(&self, key: &str) -> Option<Reply> {
+        return self.replies.get(key).cloned();
+    }
+
+    fn insert(&mut self, key: String, reply: Reply) {
+        if self.replies.insert(key.clone(), reply).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > MAX_ANSWERED {
+            match self.order.pop_front() {
+                Some(oldest) => { self.replies.remove(&oldest); },
+                None => {}
+            }
+        }
+    }
+}
+
+// Turns messages into commands for the worker pool, so whatever receives them from Slack can get
+// straight back to listening
+pub struct Dispatcher {
+    bot: Arc<Coronabot>,
+    pool: WorkerPool,
+    answered: Mutex<Answered>,
+}
+
+impl Dispatcher {
+    pub fn new(bot: Arc<Coronabot>, pool: WorkerPool) -> Dispatcher {
+        let answered = Answered{replies: HashMap::new(), order: VecDeque::new()};
+        return Dispatcher{bot: bot, pool: pool, answered: Mutex::new(answered)};
+    }
+
+    pub fn dispatch(&self, msg: IncomingMessage) {
+        let identity = self.bot.identity();
+        // Never answer ourselves or other bots, so two bots can't set each other off
+        if msg.bot_id.is_some() || msg.user.as_deref() == Some(identity.user_id.as_str()) {
+            return;
+        }
+        let mention = format!("<@{user_id}>", user_id=identity.user_id);
+        let is_mentioned = msg.text.contains(&mention);
+        if !msg.is_direct && !is_mentioned {
+            return;
+        }
+        println!("{:} in {:}: {:?}", if msg.edited { "Edited message" } else { "Message" }, msg.channel, msg.text);
+
+        // The first word is the mention itself, which is optional in DMs
+        let query = match msg.is_direct && !msg.text.trim_start().starts_with(&mention) {
+            true => msg.text.trim().to_string(),
+            false => command::rest_of(&msg.text, 1).to_string(),
+        };
+        let key = format!("{channel}|{ts}", channel=msg.channel, ts=msg.ts);
+        // Answer in the thread the mention was in, or start one on it. DMs are a conversation
+        // already, so only use a thread if they asked in one.
+        let thread_ts = match msg.is_direct {
+            true => msg.thread_ts,
+            false => Some(msg.thread_ts.unwrap_or(msg.ts)),
+        };
+        // An edit replaces the answer to the original, if we still know where it is
+        let previous = match msg.edited {
+            true => self.answered.lock().unwrap().get(&key),
+            false => None,
+        };
+        let reply = match previous {
+            Some(previous) => previous.for_edit(),
+            None => Reply::new(self.bot.web(), &msg.channel, thread_ts.as_deref()),
+        };
+        self.answered.lock().unwrap().insert(key, reply.clone());
+        self.submit(query, reply);
+    }
+
+    // Runs a slash command. The answer is only shown to whoever ran it, unless the text starts with
+    // "public", e.g. "/covid public CA".
+    pub fn dispatch_command(&self, cmd: SlashCommand) {
+        println!("Slash command in {:}: {:?}", cmd.channel, cmd.text);
+        let in_channel = cmd.text.split_whitespace().next() == Some("public");
+        let query = match in_channel {
+            true => command::rest_of(&cmd.text, 1).to_string(),
+            false => cmd.text,
+        };
+        let query = match query.is_empty() {
+            true => "help".to_string(),
+            false => query,
+        };
+        let reply = Reply::for_response_url(self.bot.web(), &cmd.channel, &cmd.response_url, in_channel);
+        self.submit(query, reply);
+    }
+
+    // Reruns the command behind a chart with a control's change applied, replacing the chart
+    pub fn dispatch_interaction(&self, interaction: Interaction) {
+        let query = match controls::apply(&interaction.query, &interaction.action) {
+            Ok(query) => query,
+            Err(err) => {
+                println!("Ignoring interaction {:?}: {:}", interaction, err);
+                return;
+            }
+        };
+        println!("Interaction in {:}: {:?}", interaction.channel, query);
+        let reply = Reply::replacing(self.bot.web(), &interaction.channel, &interaction.response_url);
+        self.submit(query, reply);
+    }
+
+    // Posts a briefing: the header, if it has one, then the answer to each command in order. It's a
+    // single job so the answers can't overtake each other. Fails instead of apologising when the
+    // pool is swamped, so the scheduler can try again on its next tick.
+    pub fn dispatch_briefing(&self, channel: &str, title: Option<String>, commands: Vec<String>) -> Result<(), String> {
+        let bot = self.bot.clone();
+        let run_channel = channel.to_string();
+        let timeout_reply = Reply::new(self.bot.web(), channel, None);
+        let job = Job{
+            name: format!("briefing {:?} in {:}", commands, channel),
+            run: Box::new(move || {
+                match title {
+                    Some(title) => Reply::new(bot.web(), &run_channel, None).send_message(&Message::new(&title).header(&title)),
+                    None => {}
+                }
+                for command in commands.iter() {
+                    bot.handle_command(command, &Reply::new(bot.web(), &run_channel, None));
+                }
+            }),
+            timed_out: Box::new(move || timeout_reply.send("Sorry, this briefing is taking too long, some of it may be late or missing.")),
+        };
+        return self.pool.submit(job);
+    }
+
+    fn submit(&self, query: String, reply: Reply) {
+        let bot = self.bot.clone();
+        let run_reply = reply.clone();
+        let timeout_reply = reply.clone();
+        let job = Job{
+            name: format!("{:?} in {:}", query, reply.channel()),
+            run: Box::new(move || bot.handle_command(&query, &run_reply)),
+            timed_out: Box::new(move || timeout_reply.close_with("Sorry, that took too long. Try again in a bit, or ask for less data.")),
+        };
+        match self.pool.submit(job) {
+            Ok(()) => {},
+            Err(err) => {
+                println!("{:}", err);
+                reply.send("Sorry, I'm swamped right now. Try again in a minute.");
+            }
+        }
+    }
+}
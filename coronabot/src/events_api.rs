@@ -0,0 +1,207 @@
+use crate::dispatch::{Dispatcher, IncomingMessage, Interaction, SlashCommand};
+use chrono::Utc;
+use hmac::{Hmac, Mac, NewMac};
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Arc;
+use tiny_http::{Header, Request, Response, Server};
+
+// Requests older than this are rejected, so a captured request can't be replayed later
+const MAX_REQUEST_AGE_SECS: i64 = 60 * 5;
+
+// Where Slack sends each kind of request, set in the app's settings
+pub struct Paths {
+    pub events: String,
+    pub commands: String,
+    pub interactions: String,
+}
+
+// Receives Events API requests, slash commands and interactions until the server dies
+pub fn serve(listen: &str, paths: &Paths, signing_secret: &str, dispatcher: Arc<Dispatcher>) {
+    let server = Server::http(listen).expect("Failed to start Events API server");
+    println!("Listening for Slack on {:} (events {:}, commands {:}, interactions {:})", listen, paths.events, paths.commands, paths.interactions);
+    for request in server.incoming_requests() {
+        handle_request(request, paths, signing_secret, &dispatcher);
+    }
+}
+
+// Slack's request signing: an HMAC of the timestamp and body, keyed with the app's signing secret.
+// See https://api.slack.com/authentication/verifying-requests-from-slack
+pub fn verify_signature(signing_secret: &str, timestamp: &str, body: &str, signature: &str) -> Result<(), String> {
+    let sent_at = timestamp.parse::<i64>().map_err(|_| format!("Bad request timestamp {:}", timestamp))?;
+    if (Utc::now().timestamp() - sent_at).abs() > MAX_REQUEST_AGE_SECS {
+        return Err(format!("Request timestamp {:} is too old", timestamp));
+    }
+    if !signature.starts_with("v0=") {
+        return Err("Unknown signature version".to_string());
+    }
+    let signature = hex::decode(&signature[3..]).map_err(|_| "Signature isn't hex".to_string())?;
+    let mut mac = Hmac::<Sha256>::new_varkey(signing_secret.as_bytes()).map_err(|_| "Bad signing secret".to_string())?;
+    mac.update(format!("v0:{timestamp}:{body}", timestamp=timestamp, body=body).as_bytes());
+    return mac.verify(&signature).map_err(|_| "Signature doesn't match".to_string());
+}
+
+pub fn header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    return request.headers().iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str());
+}
+
+fn handle_request(mut request: Request, paths: &Paths, signing_secret: &str, dispatcher: &Dispatcher) {
+    let is_command = request.url() == paths.commands;
+    let is_interaction = request.url() == paths.interactions;
+    if !is_command && !is_interaction && request.url() != paths.events {
+        respond(request, 404, "Not found".to_string());
+        return;
+    }
+    let mut body = String::new();
+    match request.as_reader().read_to_string(&mut body) {
+        Ok(_) => {},
+        Err(err) => {
+            println!("Failed to read event: {:}", err);
+            respond(request, 400, "Bad request".to_string());
+            return;
+        }
+    }
+    let verified = match (header(&request, "X-Slack-Request-Timestamp"), header(&request, "X-Slack-Signature")) {
+        (Some(timestamp), Some(signature)) => verify_signature(signing_secret, timestamp, &body, signature),
+        _ => Err("Missing signature headers".to_string()),
+    };
+    match verified {
+        Ok(()) => {},
+        Err(err) => {
+            println!("Rejected event: {:}", err);
+            respond(request, 401, "Unauthorized".to_string());
+            return;
+        }
+    }
+
+    if is_command {
+        handle_command(request, &body, dispatcher);
+        return;
+    }
+    if is_interaction {
+        handle_interaction(request, &body, dispatcher);
+        return;
+    }
+
+    // We always answer straight away, so a retry means the first attempt's response got lost
+    // rather than that it wasn't handled
+    if header(&request, "X-Slack-Retry-Num").is_some() {
+        respond(request, 200, "".to_string());
+        return;
+    }
+
+    let payload: Value = match serde_json::from_str(&body) {
+        Ok(payload) => payload,
+        Err(err) => {
+            println!("Failed to parse event: {:}", err);
+            respond(request, 400, "Bad request".to_string());
+            return;
+        }
+    };
+    match payload["type"].as_str() {
+        // Sent once when the request URL is set up in the app's settings
+        Some("url_verification") => {
+            let challenge = payload["challenge"].as_str().unwrap_or("").to_string();
+            respond(request, 200, challenge);
+        },
+        Some("event_callback") => {
+            respond(request, 200, "".to_string());
+            match IncomingMessage::from_event(&payload["event"]) {
+                Some(msg) => dispatcher.dispatch(msg),
+                None => {}
+            }
+        },
+        other => {
+            println!("Ignoring request of type {:?}", other);
+            respond(request, 200, "".to_string());
+        }
+    }
+}
+
+// Slack gives up on a slash command after 3 seconds, so it's acknowledged straight away and answered
+// later via its response_url
+fn handle_command(request: Request, body: &str, dispatcher: &Dispatcher) {
+    let fields: HashMap<String, String> = url::form_urlencoded::parse(body.as_bytes()).into_owned().collect();
+    match SlashCommand::from_fields(&fields) {
+        Some(cmd) => {
+            respond(request, 200, "".to_string());
+            dispatcher.dispatch_command(cmd);
+        },
+        None => {
+            println!("Malformed slash command: {:?}", body);
+            respond(request, 400, "Bad request".to_string());
+        }
+    }
+}
+
+// Interactions have the same 3 second limit. The payload is JSON in a form field.
+fn handle_interaction(request: Request, body: &str, dispatcher: &Dispatcher) {
+    let payload = url::form_urlencoded::parse(body.as_bytes())
+        .find(|(key, _)| key == "payload")
+        .and_then(|(_, payload)| serde_json::from_str::<Value>(&payload).ok());
+    match payload {
+        Some(payload) => {
+            respond(request, 200, "".to_string());
+            match Interaction::from_payload(&payload) {
+                Some(interaction) => dispatcher.dispatch_interaction(interaction),
+                None => {}
+            }
+        },
+        None => {
+            println!("Malformed interaction: {:?}", body);
+            respond(request, 400, "Bad request".to_string());
+        }
+    }
+}
+
+fn respond(request: Request, status: u16, body: String) {
+    let header = Header::from_bytes(&b"Content-Type"[..], &b"text/plain"[..]).unwrap();
+    match request.respond(Response::from_string(body).with_status_code(status).with_header(header)) {
+        Ok(()) => {},
+        Err(err) => println!("Failed to respond to Slack: {:}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "8f742231b10e8888abcd99yyyzzz85a5";
+    const BODY: &str = r#"{"type":"event_callback","event":{"type":"app_mention","text":"<@U1> CA"}}"#;
+
+    fn sign(timestamp: &str, body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(SECRET.as_bytes()).unwrap();
+        mac.update(format!("v0:{timestamp}:{body}", timestamp=timestamp, body=body).as_bytes());
+        return format!("v0={signature}", signature=hex::encode(mac.finalize().into_bytes()));
+    }
+
+    #[test]
+    fn accepts_signed_requests() {
+        let timestamp = Utc::now().timestamp().to_string();
+        assert_eq!(verify_signature(SECRET, &timestamp, BODY, &sign(&timestamp, BODY)), Ok(()));
+    }
+
+    #[test]
+    fn rejects_bad_signatures() {
+        let timestamp = Utc::now().timestamp().to_string();
+        let signature = sign(&timestamp, BODY);
+        assert!(verify_signature("another secret", &timestamp, BODY, &signature).is_err());
+        assert!(verify_signature(SECRET, &timestamp, r#"{"type":"event_callback","event":{"type":"app_mention","text":"<@U1> NY"}}"#, &signature).is_err());
+        assert!(verify_signature(SECRET, &timestamp, BODY, &signature.replace("v0=", "v1=")).is_err());
+        assert!(verify_signature(SECRET, &timestamp, BODY, "v0=not hex").is_err());
+        assert!(verify_signature(SECRET, &timestamp, BODY, "").is_err());
+    }
+
+    #[test]
+    fn rejects_old_or_bad_timestamps() {
+        let old = (Utc::now().timestamp() - MAX_REQUEST_AGE_SECS - 10).to_string();
+        assert!(verify_signature(SECRET, &old, BODY, &sign(&old, BODY)).is_err());
+        let future = (Utc::now().timestamp() + MAX_REQUEST_AGE_SECS + 10).to_string();
+        assert!(verify_signature(SECRET, &future, BODY, &sign(&future, BODY)).is_err());
+        assert!(verify_signature(SECRET, "yesterday", BODY, &sign("yesterday", BODY)).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use mexprp::{Term, Context, Calculation, MathError, Answer, Expression};
+use std::collections::HashMap;
+
+// Variables interpolated into custom expressions from each day's data
+pub const VARIABLES: [&str; 6] = ["positive", "negative", "total", "dead", "hospitalized", "pending"];
+pub const FUNCTIONS: [&str; 3] = ["log", "logtwo", "logten"];
+
+// Deep enough for definitions built on definitions, shallow enough to catch cycles quickly
+const MAX_EXPANSION_DEPTH: usize = 10;
+
+pub fn new_context(values: &HashMap<&str, f64>) -> Context<f64> {
+    let mut context: Context<f64> = Context::new();
+    for (name, value) in values.iter() {
+        context.set_var(name, *value);
+    }
+
+    context.set_func("log", |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+        if args.len() != 1 {
+            return Err(MathError::IncorrectArguments)
+        }
+        let a = args.get(0).unwrap().eval_ctx(ctx)?;
+        let answer = match a {
+            Answer::Single(n) => n.log2(),
+            Answer::Multiple(ns) => ns.get(0).unwrap().log2()
+        };
+        Ok(Answer::Single(answer))
+    });
+    context.set_func("logtwo", |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+        if args.len() != 1 {
+            return Err(MathError::IncorrectArguments)
+        }
+        let a = args.get(0).unwrap().eval_ctx(ctx)?;
+        let answer = match a {
+            Answer::Single(n) => n.log2(),
+            Answer::Multiple(ns) => ns.get(0).unwrap().log2()
+        };
+        Ok(Answer::Single(answer))
+    });
+    context.set_func("logten", |args: &[Term<f64>], ctx: &Context<f64>| -> Calculation<f64> {
+        if args.len() != 1 {
+            return Err(MathError::IncorrectArguments)
+        }
+        let a = args.get(0).unwrap().eval_ctx(ctx)?;
+        let answer = match a {
+            Answer::Single(n) => n.log10(),
+            Answer::Multiple(ns) => ns.get(0).unwrap().log2()
+        };
+        Ok(Answer::Single(answer))
+    });
+    return context;
+}
+
+pub fn is_reserved(name: &str) -> bool {
+    return VARIABLES.contains(&name) || FUNCTIONS.contains(&name);
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {},
+        _ => return false,
+    }
+    return chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+}
+
+// Replaces every reference to a saved definition with its (parenthesized) expression so
+// the result only refers to the built-in variables
+pub fn expand(expression: &str, definitions: &HashMap<String, String>) -> Result<String, String> {
+    return expand_depth(expression, definitions, 0);
+}
+
+fn expand_depth(expression: &str, definitions: &HashMap<String, String>, depth: usize) -> Result<String, String> {
+    if depth > MAX_EXPANSION_DEPTH {
+        return Err(format!("Definitions nest too deeply (is one defined in terms of itself?) in: {:}", expression));
+    }
+    let chars: Vec<char> = expression.chars().collect();
+    let mut ret = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if !(c.is_ascii_alphabetic() || c == '_') {
+            ret.push(c);
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+            i += 1;
+        }
+        let ident: String = chars[start..i].iter().collect();
+        match definitions.get(&ident) {
+            Some(definition) if !is_reserved(&ident) => {
+                let expanded = expand_depth(definition, definitions, depth + 1)?;
+                ret.push('(');
+                ret.push_str(&expanded);
+                ret.push(')');
+            },
+            _ => ret.push_str(&ident),
+        }
+    }
+    return Ok(ret);
+}
+
+// Checks an expression parses against the built-in variables before we save it
+pub fn validate(expression: &str) -> Result<(), String> {
+    let mut values = HashMap::new();
+    for var in VARIABLES.iter() {
+        values.insert(*var, 1.0);
+    }
+    let context = new_context(&values);
+    match Expression::parse_ctx(expression, context) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Couldn't parse expression {:}: {:?}", expression, err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definitions(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        return pairs.iter().map(|(name, expression)| (name.to_string(), expression.to_string())).collect();
+    }
+
+    #[test]
+    fn expands_nested_definitions() {
+        let defs = definitions(&[("positivity", "positive/tests"), ("tests", "positive+negative"), ("pct", "positivity*100")]);
+        assert_eq!(expand("pct", &defs), Ok("((positive/(positive+negative))*100)".to_string()));
+        assert_eq!(expand("log(positivity) + dead", &defs), Ok("log((positive/(positive+negative))) + dead".to_string()));
+        assert_eq!(expand("positive", &defs), Ok("positive".to_string()));
+    }
+
+    #[test]
+    fn catches_cycles() {
+        let defs = definitions(&[("a", "b + 1"), ("b", "a * 2")]);
+        assert!(expand("a", &defs).unwrap_err().contains("nest too deeply"));
+        let defs = definitions(&[("itself", "itself + 1")]);
+        assert!(expand("itself", &defs).is_err());
+        // A chain of MAX_EXPANSION_DEPTH definitions still expands, one more doesn't
+        let mut defs = HashMap::new();
+        for i in 0..MAX_EXPANSION_DEPTH {
+            defs.insert(format!("d{}", i), format!("d{}", i + 1));
+        }
+        defs.insert(format!("d{}", MAX_EXPANSION_DEPTH - 1), "dead".to_string());
+        assert_eq!(expand("d0", &defs), Ok(format!("{open}dead{close}", open="(".repeat(MAX_EXPANSION_DEPTH), close=")".repeat(MAX_EXPANSION_DEPTH))));
+        defs.insert(format!("d{}", MAX_EXPANSION_DEPTH - 1), format!("d{}", MAX_EXPANSION_DEPTH));
+        defs.insert(format!("d{}", MAX_EXPANSION_DEPTH), "dead".to_string());
+        assert!(expand("d0", &defs).is_err());
+    }
+
+    #[test]
+    fn whole_names_only() {
+        let defs = definitions(&[("pos", "dead"), ("neg", "1")]);
+        assert_eq!(expand("positive + pos - negative*neg", &defs), Ok("positive + (dead) - negative*(1)".to_string()));
+        assert_eq!(expand("pos_2 + _pos + pos2", &defs), Ok("pos_2 + _pos + pos2".to_string()));
+    }
+
+    #[test]
+    fn reserved_names_are_never_rewritten() {
+        let defs = definitions(&[("positive", "dead"), ("log", "total")]);
+        assert_eq!(expand("log(positive)", &defs), Ok("log(positive)".to_string()));
+        for name in ["positive", "pending", "log", "logten"].iter() {
+            assert!(is_reserved(name), "{:}", name);
+        }
+        assert!(!is_reserved("positivity"));
+        assert!(!is_reserved("Positive"));
+    }
+
+    #[test]
+    fn names() {
+        for name in ["positivity", "_x", "rate_7d", "A1"].iter() {
+            assert!(is_valid_name(name), "{:}", name);
+        }
+        for name in ["", "7d", "pos rate", "a-b", "é"].iter() {
+            assert!(!is_valid_name(name), "{:}", name);
+        }
+    }
+
+    #[test]
+    fn validates_expanded_expressions() {
+        let defs = definitions(&[("positivity", "positive/total")]);
+        assert_eq!(validate(&expand("positivity * 100", &defs).unwrap()), Ok(()));
+        assert!(validate("log((positive)").is_err());
+    }
+}
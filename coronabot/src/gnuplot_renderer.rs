@@ -0,0 +1,216 @@
+use crate::chart::{Chart, ChartSpec, LegendPosition, Scale, SeriesStyle, Theme};
+use crate::render::{ChartRenderer, ImageFormat, RenderError};
+use chrono::NaiveTime;
+use gnuplot::{Figure, Caption, Color, AxesCommon, DashType};
+use gnuplot::AlignType::{AlignLeft, AlignRight, AlignTop, AlignBottom};
+use gnuplot::ArrowheadType::NoArrow;
+use gnuplot::AutoOption::Auto;
+use gnuplot::BorderLocation2D::{Left, Bottom, Right, Top};
+use gnuplot::Coordinate::{Graph, Axis};
+use gnuplot::LabelOption::{Font, TextColor};
+use gnuplot::LegendOption::Placement;
+use gnuplot::PlotOption::{Axes, LineStyle, PointSize, ArrowType};
+use gnuplot::TickOption::{Mirror, Format};
+use gnuplot::XAxis::X1;
+use gnuplot::YAxis::{Y1, Y2};
+use crate::worker_pool::Semaphore;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Keeps a PDF chart the same proportions (and roughly the same size on screen) as the PNG
+const PDF_DPI: f32 = 100.0;
+
+// Renders charts by driving an external gnuplot process
+pub struct GnuplotRenderer {
+    // Each process takes a fair bit of memory, so only let a few run at once
+    processes: Arc<Semaphore>,
+    // How long to wait for a process slot, and then for the process
+    timeout: Duration,
+    installed: bool,
+}
+
+impl GnuplotRenderer {
+    pub fn new(max_processes: usize, timeout: Duration) -> GnuplotRenderer {
+        return GnuplotRenderer{
+            processes: Arc::new(Semaphore::new(max_processes)),
+            timeout: timeout,
+            installed: GnuplotRenderer::is_installed(),
+        };
+    }
+
+    pub fn is_installed() -> bool {
+        return Command::new("gnuplot").arg("--version").output().is_ok();
+    }
+
+    fn figure(&self, chart: &Chart, spec: &ChartSpec) -> Figure {
+        let x = &chart.x;
+        let y1 = spec.y1_scale.prepare(chart.y1.clone());
+        let y2 = spec.y2_scale.prepare(chart.y2.clone());
+        let show_y2 = spec.show_y2 && !y2.is_empty();
+        let show_legend = spec.legend != LegendPosition::Hidden;
+
+        let foreground = spec.theme.foreground();
+        let y1_color = spec.y1_color();
+        let y2_color = spec.y2_color();
+
+        let mut fg = Figure::new();
+        if spec.theme != Theme::Light {
+            fg.set_pre_commands(&format!("set object 1 rectangle from screen 0,0 to screen 1,1 fillcolor rgb '{:}' fillstyle solid noborder behind",
+                                         spec.theme.background()));
+        }
+        let axes = fg.axes2d();
+        axes.set_title(&chart.title, &[TextColor(foreground), Font(spec.font.as_str(), spec.font_size)])
+            .set_border(false, &[Left, Bottom, Right, Top], &[Color(foreground)])
+            .set_y_ticks(Some((Auto, 0)), &[Mirror(false)], &[TextColor(foreground), Font(spec.font.as_str(), spec.font_size)])  // Make Y1 not mirror.
+            .set_y_label(&chart.y1_label, &[TextColor(y1_color.as_str())])
+            .set_x_ticks(Some((Auto, 1)), &[Mirror(false), Format(spec.date_format.as_str())], &[TextColor(foreground), Font(spec.font.as_str(), spec.font_size)])
+            .set_x_time(true);
+
+        let mut y1_options = vec![Axes(X1, Y1), Color(y1_color.as_str())];
+        if show_legend {
+            y1_options.push(Caption(chart.y1_label.as_str()));
+        }
+        match spec.style {
+            SeriesStyle::Lines => {
+                y1_options.push(PointSize(0.0));
+                axes.lines_points(x, &y1, &y1_options);
+            },
+            SeriesStyle::Bars => {
+                axes.boxes(x, &y1, &y1_options);
+            }
+        }
+        if spec.y1_scale == Scale::Log {
+            axes.set_y_log(Some(10.0));
+        }
+
+        if show_y2 {
+            let mut y2_options = vec![Axes(X1, Y2), Color(y2_color.as_str()), PointSize(0.0)];
+            if show_legend {
+                y2_options.push(Caption(chart.y2_label.as_str()));
+            }
+            // The secondary series is always a line so it stays readable over bars
+            axes.lines_points(x, &y2, &y2_options)
+                .set_y2_ticks(Some((Auto, 0)), &[Mirror(false), Format("%.2f")], &[TextColor(foreground), Font(spec.font.as_str(), spec.font_size)])  // Make Y2 not mirror, and visible.
+                .set_y2_label(&chart.y2_label, &[TextColor(y2_color.as_str())]);
+            if spec.y2_scale == Scale::Log {
+                axes.set_y2_log(Some(10.0));
+            }
+        }
+
+        if show_legend {
+            let (legend_x, legend_y, align_x, align_y) = match spec.legend {
+                LegendPosition::TopLeft => (Graph(0.0), Graph(1.0), AlignLeft, AlignTop),
+                LegendPosition::BottomLeft => (Graph(0.0), Graph(0.0), AlignLeft, AlignBottom),
+                LegendPosition::BottomRight => (Graph(1.0), Graph(0.0), AlignRight, AlignBottom),
+                _ => (Graph(1.0), Graph(1.0), AlignRight, AlignTop),
+            };
+            axes.set_legend(legend_x, legend_y, &[Placement(align_x, align_y)], &[TextColor(foreground)]);
+        }
+
+        for annotation in spec.annotations.iter() {
+            let ts = annotation.date.and_time(NaiveTime::from_hms(0, 0, 0)).timestamp() as f64;
+            axes.arrow(Axis(ts), Graph(0.0), Axis(ts), Graph(1.0),
+                       &[ArrowType(NoArrow), LineStyle(DashType::Dash), Color(foreground)]);
+            axes.label(&annotation.label, Axis(ts), Graph(1.02), &[TextColor(foreground), Font(spec.font.as_str(), spec.font_size * 0.8)]);
+        }
+        return fg;
+    }
+}
+
+impl ChartRenderer for GnuplotRenderer {
+    fn name(&self) -> &'static str {
+        return "gnuplot";
+    }
+
+    fn render(&self, chart: &Chart, spec: &ChartSpec) -> Result<Vec<u8>, RenderError> {
+        if !self.installed {
+            return Err(RenderError::Unavailable("gnuplot isn't installed".to_string()));
+        }
+        let mut fg = self.figure(chart, spec);
+
+        // With no output file gnuplot writes the image to stdout, so it never touches the disk
+        let terminal = match spec.format {
+            ImageFormat::Png => format!("pngcairo size {},{}", spec.width, spec.height),
+            ImageFormat::Svg => format!("svg size {},{}", spec.width, spec.height),
+            // pdfcairo sizes are in inches
+            ImageFormat::Pdf => format!("pdfcairo size {},{}", spec.width as f32 / PDF_DPI, spec.height as f32 / PDF_DPI),
+        };
+        fg.set_terminal(&terminal, "");
+        let mut script = Vec::new();
+        fg.echo(&mut script);
+        let _permit = match self.processes.acquire(self.timeout) {
+            Some(permit) => permit,
+            None => return Err(RenderError::Failed(format!("no gnuplot process free after {:?}", self.timeout))),
+        };
+        return run_gnuplot(&script, self.timeout);
+    }
+}
+
+// Feeds script to a gnuplot process and returns whatever it wrote to stdout, killing it if it's
+// still going after timeout. The process is always waited on, whatever happens, so nothing is
+// left behind.
+fn run_gnuplot(script: &[u8], timeout: Duration) -> Result<Vec<u8>, RenderError> {
+    let mut child = Command::new("gnuplot")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| RenderError::Unavailable(err.to_string()))?;
+
+    // Read output as it comes so gnuplot never blocks on a full pipe
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let stdout_reader = thread::spawn(move || read_all(stdout));
+    let stderr_reader = thread::spawn(move || read_all(stderr));
+
+    // Dropping stdin closes it, which tells gnuplot the script is finished
+    let written = match child.stdin.take() {
+        Some(mut stdin) => stdin.write_all(script),
+        None => Ok(()),
+    };
+
+    // Poll rather than block so a stuck gnuplot can be killed
+    let start = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Ok(status),
+            Ok(None) if start.elapsed() > timeout => {
+                let _ = child.kill();
+                let _ = child.wait();
+                break Err(RenderError::Failed(format!("gnuplot took longer than {:?}", timeout)));
+            },
+            Ok(None) => thread::sleep(Duration::from_millis(20)),
+            Err(err) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                break Err(RenderError::Failed(err.to_string()));
+            }
+        }
+    };
+    let stdout = stdout_reader.join().unwrap_or(Vec::new());
+    let stderr = stderr_reader.join().unwrap_or(Vec::new());
+
+    let status = status?;
+    match written {
+        Ok(()) => {},
+        Err(err) => return Err(RenderError::Failed(format!("couldn't send script to gnuplot: {:}", err))),
+    }
+    if !status.success() || stdout.is_empty() {
+        return Err(RenderError::Failed(format!("gnuplot exited with {:}: {:}", status, String::from_utf8_lossy(&stderr))));
+    }
+    return Ok(stdout);
+}
+
+fn read_all<R: Read>(pipe: Option<R>) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    match pipe {
+        Some(mut pipe) => {
+            let _ = pipe.read_to_end(&mut buffer);
+        },
+        None => {}
+    }
+    return buffer;
+}
@@ -0,0 +1,242 @@
+use crate::config::ImageStoreConfig;
+use crate::slack_web::SlackWeb;
+use s3::bucket::Bucket;
+use s3::credentials::Credentials;
+use s3::region::Region;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::thread;
+use tiny_http::{Header, Response, Server};
+
+// A rendered chart on its way to wherever the deployment keeps them
+pub struct ChartUpload<'a> {
+    pub name: &'a str,
+    pub bytes: &'a [u8],
+    pub content_type: &'a str,
+    pub title: &'a str,
+    pub alt_text: &'a str,
+    // The channel the chart was asked for in, and the thread to answer in if any
+    pub channel: &'a str,
+    pub thread_ts: Option<&'a str>,
+}
+
+pub enum Stored {
+    // Somewhere the chart can be linked from
+    Url(String),
+    // Already shared in the channel, nothing left to post
+    Posted,
+}
+
+// Somewhere to put rendered charts so they can be shown in Slack
+pub trait ImageStore: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn store(&self, upload: &ChartUpload) -> Result<Stored, String>;
+
+    // Whether stored charts get a URL. Ones that don't are posted in the conversation for everyone
+    // in it to see, so can't answer privately.
+    fn gives_urls(&self) -> bool {
+        return true;
+    }
+
+    // Removes a chart previously stored under name
+    fn delete(&self, name: &str) -> Result<(), String>;
+}
+
+pub fn from_config(config: &ImageStoreConfig, token: &str) -> Box<dyn ImageStore> {
+    let store: Box<dyn ImageStore> = match config {
+        ImageStoreConfig::S3{bucket, region, endpoint, prefix, public_url, access_key, secret_key} => {
+            Box::new(S3Store::new(bucket, region, endpoint.as_ref(), prefix, public_url.as_ref(), access_key.as_ref(), secret_key.as_ref()))
+        },
+        ImageStoreConfig::Local{dir, public_url, listen} => {
+            Box::new(LocalStore::new(dir, public_url, listen.as_ref()))
+        },
+        ImageStoreConfig::Slack => {
+            Box::new(SlackStore{web: SlackWeb::new(token)})
+        },
+    };
+    println!("Storing charts with {:}", store.name());
+    return store;
+}
+
+fn join_url(base: &str, path: &str) -> String {
+    let mut url = base.trim_end_matches("/").to_string();
+    url.push_str("/");
+    url.push_str(path.trim_start_matches("/"));
+    return url;
+}
+
+// Any S3-compatible object store: AWS itself, or MinIO/localstack via a custom endpoint
+pub struct S3Store {
+    bucket: Bucket,
+    prefix: String,
+    public_url: String,
+}
+
+impl S3Store {
+    pub fn new(bucket_name: &str,
+               region: &str,
+               endpoint: Option<&String>,
+               prefix: &str,
+               public_url: Option<&String>,
+               access_key: Option<&String>,
+               secret_key: Option<&String>) -> S3Store {
+        let s3_region = match endpoint {
+            Some(endpoint) => Region::Custom{region: region.to_string(), endpoint: endpoint.to_string()},
+            None => region.parse().expect("Unknown S3 region"),
+        };
+        let credentials = match (access_key, secret_key) {
+            (Some(access_key), Some(secret_key)) => Credentials::new(Some(access_key.to_string()), Some(secret_key.to_string()), None, None),
+            _ => Credentials::default(),
+        };
+        let bucket = Bucket::new(bucket_name, s3_region, credentials).expect("Failed to set up S3 bucket");
+
+        // Without an explicit public URL assume the bucket is publicly readable at its usual address
+        let public_url = match (public_url, endpoint) {
+            (Some(public_url), _) => public_url.to_string(),
+            (None, Some(endpoint)) => join_url(endpoint, bucket_name),
+            (None, None) => format!("https://{bucket}.s3.amazonaws.com/", bucket=bucket_name),
+        };
+        return S3Store{bucket: bucket, prefix: prefix.to_string(), public_url: public_url};
+    }
+}
+
+impl ImageStore for S3Store {
+    fn name(&self) -> &'static str {
+        return "s3";
+    }
+
+    fn store(&self, upload: &ChartUpload) -> Result<Stored, String> {
+        let path = join_url(&self.prefix, upload.name);
+        let res = self.bucket.put_object_blocking(&path, upload.bytes, upload.content_type);
+        match res {
+            Ok((_, code)) if code >= 200 && code < 300 => {
+                let public_url = join_url(&self.public_url, &path);
+                println!("Stored in s3: {:}", &public_url);
+                return Ok(Stored::Url(public_url));
+            },
+            Ok((body, code)) => {
+                return Err(format!("S3 upload failed with status {:}: {:}", code, String::from_utf8_lossy(&body)));
+            },
+            Err(err) => {
+                return Err(format!("S3 upload failed: {:?}", err));
+            }
+        }
+    }
+
+    fn delete(&self, name: &str) -> Result<(), String> {
+        let path = join_url(&self.prefix, name);
+        match self.bucket.delete_object_blocking(&path) {
+            Ok((_, code)) if code >= 200 && code < 300 => return Ok(()),
+            Ok((body, code)) => {
+                return Err(format!("S3 delete failed with status {:}: {:}", code, String::from_utf8_lossy(&body)));
+            },
+            Err(err) => {
+                return Err(format!("S3 delete failed: {:?}", err));
+            }
+        }
+    }
+}
+
+// Writes charts to a local directory, optionally serving it over HTTP for deployments without S3
+pub struct LocalStore {
+    dir: PathBuf,
+    public_url: String,
+}
+
+impl LocalStore {
+    pub fn new(dir: &str, public_url: &str, listen: Option<&String>) -> LocalStore {
+        fs::create_dir_all(dir).expect("Failed to create chart directory");
+        match listen {
+            Some(addr) => serve_directory(PathBuf::from(dir), addr),
+            None => {}
+        }
+        return LocalStore{dir: PathBuf::from(dir), public_url: public_url.to_string()};
+    }
+}
+
+impl ImageStore for LocalStore {
+    fn name(&self) -> &'static str {
+        return "local";
+    }
+
+    fn store(&self, upload: &ChartUpload) -> Result<Stored, String> {
+        let path = self.dir.join(upload.name);
+        match fs::write(&path, upload.bytes) {
+            Ok(()) => {
+                let public_url = join_url(&self.public_url, upload.name);
+                println!("Stored locally: {:}", &public_url);
+                return Ok(Stored::Url(public_url));
+            },
+            Err(err) => {
+                return Err(format!("Failed to write {:?}: {:}", path, err));
+            }
+        }
+    }
+
+    fn delete(&self, name: &str) -> Result<(), String> {
+        let path = self.dir.join(name);
+        return fs::remove_file(&path).map_err(|err| format!("Failed to remove {:?}: {:}", path, err));
+    }
+}
+
+// Attaches charts straight to a message in the channel, so they're only visible inside the
+// workspace and no AWS credentials are needed
+pub struct SlackStore {
+    web: SlackWeb,
+}
+
+impl ImageStore for SlackStore {
+    fn name(&self) -> &'static str {
+        return "slack";
+    }
+
+    fn store(&self, upload: &ChartUpload) -> Result<Stored, String> {
+        self.web.upload_file(upload.channel, upload.thread_ts, upload.name, upload.bytes, upload.title, upload.alt_text)?;
+        println!("Uploaded {:} to Slack channel {:}", upload.name, upload.channel);
+        return Ok(Stored::Posted);
+    }
+
+    fn gives_urls(&self) -> bool {
+        return false;
+    }
+
+    // Posted charts aren't cached, so there's never anything to delete
+    fn delete(&self, _name: &str) -> Result<(), String> {
+        return Ok(());
+    }
+}
+
+fn content_type_for(name: &str) -> &'static str {
+    if name.ends_with(".png") {
+        return "image/png";
+    } else if name.ends_with(".svg") {
+        return "image/svg+xml";
+    } else if name.ends_with(".pdf") {
+        return "application/pdf";
+    }
+    return "application/octet-stream";
+}
+
+// A bare-bones static file server for the chart directory. Only serves files directly inside it.
+fn serve_directory(dir: PathBuf, addr: &str) {
+    let server = Server::http(addr).expect("Failed to start chart file server");
+    println!("Serving charts from {:?} on {:}", dir, addr);
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let name = request.url().trim_start_matches("/").split("?").next().unwrap_or("").to_string();
+            let valid = !name.is_empty() && !name.contains("/") && !name.contains("\\") && !name.starts_with(".");
+            let file = if valid { File::open(dir.join(&name)).ok() } else { None };
+            let res = match file {
+                Some(file) => {
+                    let header = Header::from_bytes(&b"Content-Type"[..], content_type_for(&name).as_bytes()).unwrap();
+                    request.respond(Response::from_file(file).with_header(header))
+                },
+                None => request.respond(Response::from_string("Not found").with_status_code(404)),
+            };
+            match res {
+                Ok(()) => {},
+                Err(err) => println!("Failed to serve {:}: {:}", name, err),
+            }
+        }
+    });
+}
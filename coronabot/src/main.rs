@@ -1,27 +1,97 @@
+mod alerts;
+mod blocks;
+mod briefings;
+mod chart;
+mod chart_cache;
+mod command;
+mod config;
+mod controls;
 mod coronabot;
+mod daterange;
+mod dispatch;
+mod events_api;
+mod expressions;
+mod gnuplot_renderer;
+mod image_store;
+mod plotters_renderer;
+mod regions;
+mod render;
+mod reply;
+mod rtm;
+mod schedule;
+mod slack_web;
+mod socket_mode;
+mod store;
+mod worker_pool;
 extern crate reqwest;
 extern crate slack;
 
 use slack::RtmClient;
 use crate::coronabot::Coronabot;
+use crate::config::{Config, TransportConfig};
+use crate::dispatch::Dispatcher;
+use crate::rtm::RtmHandler;
+use crate::slack_web::SlackWeb;
+use crate::worker_pool::WorkerPool;
+use std::sync::Arc;
+use std::time::Duration;
 use chrono::{DateTime, Utc, FixedOffset};
 
 const USDAILY_URL: &str = "https://covidtracking.com/api/us/daily";
 const STATESDAILY_URL: &str = "https://covidtracking.com/api/states/daily";
+const DEFAULT_CONFIG_PATH: &str = "coronabot.json";
 
 fn main() {
     println!("Starting Coronabot");
+    // coronabot <api key> [config path]
     let args: Vec<String> = std::env::args().collect();
     let api_key = args[1].clone();
-    let bot_id = args[2].clone();
     println!("API key: {:?}", api_key);
-    println!("Bot id: {:?}", bot_id);
-    let mut handler = Coronabot::new(bot_id);
-    handler.start_bg_update();
+    // The bot's user id used to be passed before the config path. It's looked up now, but still
+    // accepted so existing setups keep working.
+    let mut rest = &args[2..];
+    if rest.first().map(|arg| is_user_id(arg)).unwrap_or(false) {
+        println!("Ignoring bot id argument {:?}, it's looked up from Slack now", rest[0]);
+        rest = &rest[1..];
+    }
+    let config_path = rest.first().cloned().unwrap_or(DEFAULT_CONFIG_PATH.to_string());
+    let config = Config::load(&config_path);
+    let workers = config.workers.clone();
+    let transport = config.transport.clone();
+    let briefings = config.briefings.clone();
+
+    let identity = match SlackWeb::new(&api_key).identity() {
+        Ok(identity) => identity,
+        Err(err) => panic!("Failed to look up the bot's user id: {:}", err),
+    };
+    println!("Bot id: {:?}", identity.user_id);
+    let bot = Arc::new(Coronabot::new(identity, api_key.clone(), config));
+    bot.start_bg_update();
+
+    let pool = WorkerPool::new(workers.threads, workers.queue_size, Duration::from_secs(workers.timeout_secs));
+    let dispatcher = Arc::new(Dispatcher::new(bot.clone(), pool));
+    briefings::start(bot, dispatcher.clone(), &briefings);
 
-    let r = RtmClient::login_and_run(&api_key, &mut handler);
-    match r {
-        Ok(_) => {}
-        Err(err) => println!("Error: {}", err),
+    match transport {
+        TransportConfig::Rtm => {
+            let mut handler = RtmHandler::new(dispatcher);
+            let r = RtmClient::login_and_run(&api_key, &mut handler);
+            match r {
+                Ok(_) => {}
+                Err(err) => println!("Error: {}", err),
+            }
+        },
+        TransportConfig::Events{listen, path, commands_path, interactions_path, signing_secret} => {
+            let paths = events_api::Paths{events: path, commands: commands_path, interactions: interactions_path};
+            events_api::serve(&listen, &paths, &signing_secret, dispatcher);
+        },
+        TransportConfig::Socket{app_token} => {
+            socket_mode::run(&app_token, dispatcher);
+        },
     }
 }
+
+// User ids look like U012AB3CD (or W... on Enterprise Grid), config paths don't
+fn is_user_id(arg: &str) -> bool {
+    return (arg.starts_with("U") || arg.starts_with("W")) && arg.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+}
@@ -0,0 +1,311 @@
+use crate::chart::{self, Chart, ChartSpec, LegendPosition, Scale, SeriesStyle};
+use crate::render::{ChartRenderer, ImageFormat, RenderError};
+use chrono::NaiveDateTime;
+use image::{ImageBuffer, Rgb};
+use image::png::PngEncoder;
+use plotters::coord::Shift;
+use plotters::coord::ranged1d::{AsRangedCoord, ValueFormatter};
+use plotters::prelude::*;
+use plotters::style::{register_font, FontStyle};
+use std::fmt::Write;
+use std::ops::Range;
+
+// All text is drawn in this embedded font rather than one looked up on the system, which slim
+// images don't have. The font option only applies to gnuplot.
+const FONT: &str = "sans-serif";
+const FONT_DATA: &[u8] = include_bytes!("../fonts/DejaVuSans.ttf");
+
+// Renders charts in-process, no external binaries or system fonts needed
+pub struct PlottersRenderer {}
+
+impl PlottersRenderer {
+    pub fn new() -> Result<PlottersRenderer, RenderError> {
+        match register_font(FONT, FontStyle::Normal, FONT_DATA) {
+            Ok(()) => {},
+            Err(_) => return Err(RenderError::Unavailable("the embedded chart font couldn't be loaded".to_string())),
+        }
+        return Ok(PlottersRenderer{});
+    }
+}
+
+impl ChartRenderer for PlottersRenderer {
+    fn name(&self) -> &'static str {
+        return "plotters";
+    }
+
+    fn render(&self, chart: &Chart, spec: &ChartSpec) -> Result<Vec<u8>, RenderError> {
+        let size = (spec.width, spec.height);
+        match spec.format {
+            ImageFormat::Png => {
+                let mut buffer = vec![0u8; (spec.width * spec.height * 3) as usize];
+                {
+                    let root = BitMapBackend::with_buffer(&mut buffer, size).into_drawing_area();
+                    draw(&root, chart, spec)?;
+                    root.present().map_err(|err| RenderError::Failed(err.to_string()))?;
+                }
+                let image: ImageBuffer<Rgb<u8>, Vec<u8>> = match ImageBuffer::from_raw(spec.width, spec.height, buffer) {
+                    Some(image) => image,
+                    None => return Err(RenderError::Failed("bitmap buffer has the wrong size".to_string())),
+                };
+                let mut png = Vec::new();
+                PngEncoder::new(&mut png)
+                    .encode(&image, spec.width, spec.height, image::ColorType::Rgb8)
+                    .map_err(|err| RenderError::Failed(err.to_string()))?;
+                return Ok(png);
+            },
+            ImageFormat::Svg => {
+                let mut svg = String::new();
+                {
+                    let root = SVGBackend::with_string(&mut svg, size).into_drawing_area();
+                    draw(&root, chart, spec)?;
+                    root.present().map_err(|err| RenderError::Failed(err.to_string()))?;
+                }
+                return Ok(svg.into_bytes());
+            },
+            ImageFormat::Pdf => {
+                return Err(RenderError::Unsupported("PDF charts need the gnuplot renderer".to_string()));
+            }
+        }
+    }
+}
+
+fn color_or(color: &str, fallback: &str) -> RGBColor {
+    let (r, g, b) = chart::parse_color(color).or(chart::parse_color(fallback)).unwrap_or((0, 0, 0));
+    return RGBColor(r, g, b);
+}
+
+// Only the points that can actually be drawn, log scales turn non-positive values into NaN
+fn points(x: &Vec<i64>, y: &Vec<f32>) -> Vec<(i64, f32)> {
+    return x.iter().zip(y.iter())
+        .filter(|(_, y)| y.is_finite())
+        .map(|(x, y)| (*x, *y))
+        .collect();
+}
+
+fn format_value(value: &f32) -> String {
+    if value.fract() == 0.0 {
+        return format!("{:.0}", value);
+    }
+    return format!("{:.2}", value);
+}
+
+// Date formats are checked when they're parsed, but to_string would panic on a bad one, so don't
+// rely on it
+fn format_date(ts: i64, date_format: &str) -> String {
+    let mut label = String::new();
+    match write!(label, "{}", NaiveDateTime::from_timestamp(ts, 0).format(date_format)) {
+        Ok(()) => return label,
+        Err(_) => return String::new(),
+    }
+}
+
+fn value_range(points: &Vec<(i64, f32)>, scale: Scale) -> Range<f32> {
+    let mut min = points.iter().map(|(_, y)| *y).fold(std::f32::INFINITY, f32::min);
+    let mut max = points.iter().map(|(_, y)| *y).fold(std::f32::NEG_INFINITY, f32::max);
+    if !min.is_finite() || !max.is_finite() {
+        min = 0.0;
+        max = 1.0;
+    }
+    match scale {
+        Scale::Linear => {
+            let min = min.min(0.0);
+            if max <= min {
+                max = min + 1.0;
+            }
+            return min..max * 1.05;
+        },
+        Scale::Log => {
+            if max <= min {
+                max = min * 10.0;
+            }
+            return min..max * 1.5;
+        }
+    }
+}
+
+fn draw<DB: DrawingBackend>(root: &DrawingArea<DB, Shift>, chart: &Chart, spec: &ChartSpec) -> Result<(), RenderError> {
+    let y1_points = points(&chart.x, &spec.y1_scale.prepare(chart.y1.clone()));
+    let y2_points = points(&chart.x, &spec.y2_scale.prepare(chart.y2.clone()));
+    let x_min = chart.x.iter().min().cloned().unwrap_or(0);
+    let x_max = chart.x.iter().max().cloned().unwrap_or(0).max(x_min + 1);
+    let x_range = x_min..x_max;
+    let y1_range = value_range(&y1_points, spec.y1_scale);
+    let y2_range = value_range(&y2_points, spec.y2_scale);
+
+    // Each combination of scales is a different coordinate type, so dispatch once here
+    match (spec.y1_scale, spec.y2_scale) {
+        (Scale::Linear, Scale::Linear) => draw_with(root, chart, spec, x_range, y1_range, y2_range, &y1_points, &y2_points),
+        (Scale::Linear, Scale::Log) => draw_with(root, chart, spec, x_range, y1_range, y2_range.log_scale(), &y1_points, &y2_points),
+        (Scale::Log, Scale::Linear) => draw_with(root, chart, spec, x_range, y1_range.log_scale(), y2_range, &y1_points, &y2_points),
+        (Scale::Log, Scale::Log) => draw_with(root, chart, spec, x_range, y1_range.log_scale(), y2_range.log_scale(), &y1_points, &y2_points),
+    }
+}
+
+fn draw_with<DB, Y1, Y2>(root: &DrawingArea<DB, Shift>,
+                         chart: &Chart,
+                         spec: &ChartSpec,
+                         x_range: Range<i64>,
+                         y1_range: Y1,
+                         y2_range: Y2,
+                         y1_points: &Vec<(i64, f32)>,
+                         y2_points: &Vec<(i64, f32)>) -> Result<(), RenderError>
+    where DB: DrawingBackend,
+          Y1: AsRangedCoord<Value=f32>,
+          Y1::CoordDescType: ValueFormatter<f32>,
+          Y2: AsRangedCoord<Value=f32>,
+          Y2::CoordDescType: ValueFormatter<f32> {
+    let failed = |err: DrawingAreaErrorKind<DB::ErrorType>| RenderError::Failed(err.to_string());
+
+    let background = color_or(spec.theme.background(), "white");
+    let foreground = color_or(spec.theme.foreground(), "black");
+    let y1_color = color_or(&spec.y1_color(), spec.theme.foreground());
+    let y2_color = color_or(&spec.y2_color(), "blue");
+    let font = FONT;
+    let font_size = spec.font_size;
+    let show_y2 = spec.show_y2 && !y2_points.is_empty();
+
+    root.fill(&background).map_err(failed)?;
+
+    let mut builder = ChartBuilder::on(root);
+    builder.caption(&chart.title, (font, font_size * 1.4).into_font().color(&foreground))
+        .margin(10)
+        .x_label_area_size(35)
+        .y_label_area_size(60);
+    if show_y2 {
+        builder.right_y_label_area_size(60);
+    }
+    // The secondary coordinates have to be set before the mesh is drawn, otherwise the primary
+    // labels get drawn on the right hand axis as well
+    let mut ctx = builder.build_cartesian_2d(x_range.clone(), y1_range).map_err(failed)?
+        .set_secondary_coord(x_range.clone(), y2_range);
+
+    let date_format = spec.date_format.clone();
+    let date_label = move |ts: &i64| format_date(*ts, &date_format);
+    ctx.configure_mesh()
+        .disable_mesh()
+        .axis_style(&foreground)
+        .label_style((font, font_size).into_font().color(&foreground))
+        .axis_desc_style((font, font_size).into_font().color(&y1_color))
+        .x_label_formatter(&date_label)
+        .y_label_formatter(&format_value)
+        .y_desc(&chart.y1_label)
+        .draw()
+        .map_err(failed)?;
+
+    match spec.style {
+        SeriesStyle::Lines => {
+            ctx.draw_series(LineSeries::new(y1_points.clone(), &y1_color))
+                .map_err(failed)?
+                .label(&chart.y1_label)
+                .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], &y1_color));
+        },
+        SeriesStyle::Bars => {
+            // Bars are most of a day wide so adjacent days don't touch
+            let half_width = 60 * 60 * 10;
+            ctx.draw_series(y1_points.iter().map(|(x, y)| {
+                Rectangle::new([(*x - half_width, 0.0), (*x + half_width, *y)], y1_color.filled())
+            }))
+                .map_err(failed)?
+                .label(&chart.y1_label)
+                .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 10, y + 5)], y1_color.filled()));
+        }
+    }
+
+    for annotation in spec.annotations.iter() {
+        let ts = annotation.date.and_hms(0, 0, 0).timestamp();
+        if ts < x_range.start || ts > x_range.end {
+            continue;
+        }
+        let (top, bottom) = match ctx.plotting_area().get_pixel_range() {
+            (_, y_pixels) => (y_pixels.start, y_pixels.end),
+        };
+        let (x_pixel, _) = ctx.backend_coord(&(ts, y1_points.first().map(|p| p.1).unwrap_or(0.0)));
+        root.draw(&PathElement::new(vec![(x_pixel, top), (x_pixel, bottom)], foreground.mix(0.6)))
+            .map_err(failed)?;
+        root.draw(&Text::new(annotation.label.clone(), (x_pixel + 3, top + 3), (font, font_size * 0.8).into_font().color(&foreground)))
+            .map_err(failed)?;
+    }
+
+    if show_y2 {
+        ctx.configure_secondary_axes()
+            .axis_style(&foreground)
+            .label_style((font, font_size).into_font().color(&foreground))
+            .axis_desc_style((font, font_size).into_font().color(&y2_color))
+            .y_label_formatter(&format_value)
+            .y_desc(&chart.y2_label)
+            .draw()
+            .map_err(failed)?;
+        // The secondary series is always a line so it stays readable over bars
+        ctx.draw_secondary_series(LineSeries::new(y2_points.clone(), &y2_color))
+            .map_err(failed)?
+            .label(&chart.y2_label)
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], &y2_color));
+    }
+    return draw_legend(&mut ctx, spec, background, foreground, font, font_size).map_err(failed);
+}
+
+fn draw_legend<'a, DB, CT>(ctx: &mut ChartContext<'a, DB, CT>,
+                            spec: &ChartSpec,
+                            background: RGBColor,
+                            foreground: RGBColor,
+                            font: &str,
+                            font_size: f64) -> Result<(), DrawingAreaErrorKind<DB::ErrorType>>
+    where DB: DrawingBackend + 'a,
+          CT: CoordTranslate {
+    let position = match spec.legend {
+        LegendPosition::Hidden => return Ok(()),
+        LegendPosition::TopLeft => SeriesLabelPosition::UpperLeft,
+        LegendPosition::TopRight => SeriesLabelPosition::UpperRight,
+        LegendPosition::BottomLeft => SeriesLabelPosition::LowerLeft,
+        LegendPosition::BottomRight => SeriesLabelPosition::LowerRight,
+    };
+    return ctx.configure_series_labels()
+        .position(position)
+        .background_style(&background)
+        .border_style(&foreground)
+        .label_font((font, font_size).into_font().color(&foreground))
+        .draw();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn small_chart() -> Chart {
+        let start = NaiveDate::from_ymd(2020, 5, 1).and_hms(0, 0, 0).timestamp();
+        return Chart{
+            title: "Test".to_string(),
+            x: (0..5).map(|day| start + day * 86400).collect(),
+            y1: vec![1.0, 3.0, 2.0, 5.0, 4.0],
+            y2: vec![0.1, 0.2, 0.1, 0.3, 0.2],
+            y1_label: "positive".to_string(),
+            y2_label: "positivity".to_string(),
+        };
+    }
+
+    fn spec(format: ImageFormat) -> ChartSpec {
+        return ChartSpec{width: 200, height: 100, show_y2: true, format: format, ..ChartSpec::default()};
+    }
+
+    #[test]
+    fn renders_png() {
+        let renderer = PlottersRenderer::new().unwrap();
+        let png = renderer.render(&small_chart(), &spec(ImageFormat::Png)).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+    }
+
+    #[test]
+    fn renders_svg() {
+        let renderer = PlottersRenderer::new().unwrap();
+        let svg = String::from_utf8(renderer.render(&small_chart(), &spec(ImageFormat::Svg)).unwrap()).unwrap();
+        assert!(svg.contains("<svg"));
+        assert!(svg.contains("Test"));
+    }
+
+    #[test]
+    fn pdf_is_unsupported() {
+        let renderer = PlottersRenderer::new().unwrap();
+        assert!(matches!(renderer.render(&small_chart(), &spec(ImageFormat::Pdf)), Err(RenderError::Unsupported(_))));
+    }
+}
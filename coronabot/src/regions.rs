@@ -0,0 +1,219 @@
+// Postal code, name and FIPS code of every state and territory the API reports on
+pub const STATES: &[(&str, &str, &str)] = &[
+    ("AL", "Alabama", "01"),
+    ("AK", "Alaska", "02"),
+    ("AZ", "Arizona", "04"),
+    ("AR", "Arkansas", "05"),
+    ("CA", "California", "06"),
+    ("CO", "Colorado", "08"),
+    ("CT", "Connecticut", "09"),
+    ("DE", "Delaware", "10"),
+    ("DC", "District of Columbia", "11"),
+    ("FL", "Florida", "12"),
+    ("GA", "Georgia", "13"),
+    ("HI", "Hawaii", "15"),
+    ("ID", "Idaho", "16"),
+    ("IL", "Illinois", "17"),
+    ("IN", "Indiana", "18"),
+    ("IA", "Iowa", "19"),
+    ("KS", "Kansas", "20"),
+    ("KY", "Kentucky", "21"),
+    ("LA", "Louisiana", "22"),
+    ("ME", "Maine", "23"),
+    ("MD", "Maryland", "24"),
+    ("MA", "Massachusetts", "25"),
+    ("MI", "Michigan", "26"),
+    ("MN", "Minnesota", "27"),
+    ("MS", "Mississippi", "28"),
+    ("MO", "Missouri", "29"),
+    ("MT", "Montana", "30"),
+    ("NE", "Nebraska", "31"),
+    ("NV", "Nevada", "32"),
+    ("NH", "New Hampshire", "33"),
+    ("NJ", "New Jersey", "34"),
+    ("NM", "New Mexico", "35"),
+    ("NY", "New York", "36"),
+    ("NC", "North Carolina", "37"),
+    ("ND", "North Dakota", "38"),
+    ("OH", "Ohio", "39"),
+    ("OK", "Oklahoma", "40"),
+    ("OR", "Oregon", "41"),
+    ("PA", "Pennsylvania", "42"),
+    ("RI", "Rhode Island", "44"),
+    ("SC", "South Carolina", "45"),
+    ("SD", "South Dakota", "46"),
+    ("TN", "Tennessee", "47"),
+    ("TX", "Texas", "48"),
+    ("UT", "Utah", "49"),
+    ("VT", "Vermont", "50"),
+    ("VA", "Virginia", "51"),
+    ("WA", "Washington", "53"),
+    ("WV", "West Virginia", "54"),
+    ("WI", "Wisconsin", "55"),
+    ("WY", "Wyoming", "56"),
+    ("AS", "American Samoa", "60"),
+    ("GU", "Guam", "66"),
+    ("MP", "Northern Mariana Islands", "69"),
+    ("PR", "Puerto Rico", "72"),
+    ("VI", "Virgin Islands", "78"),
+];
+
+// Shortest name prefix accepted on its own, so "Mass" works but "New" doesn't pick a state at random
+const MIN_PREFIX: usize = 4;
+
+// Lowercase with punctuation dropped and spaces/underscores collapsed, so "New_York", "new york"
+// and "N.Y." compare equal to what they should
+fn normalize(name: &str) -> String {
+    let cleaned: String = name.to_lowercase()
+        .chars()
+        .map(|c| if c == '_' || c == '-' { ' ' } else { c })
+        .filter(|c| c.is_alphanumeric() || *c == ' ')
+        .collect();
+    let words: Vec<&str> = cleaned.split_whitespace().collect();
+    return words.join(" ");
+}
+
+// Turns a postal code (any case), full name, FIPS code or unambiguous abbreviation of a name
+// into a postal code. Otherwise the error suggests the closest match, if there's a plausible one.
+pub fn resolve(name: &str) -> Result<&'static str, String> {
+    let query = normalize(name);
+    if query.is_empty() {
+        return Err("Missing state".to_string());
+    }
+
+    // FIPS codes with or without the leading zero
+    let fips_query = if query.chars().all(|c| c.is_ascii_digit()) { query.parse::<u32>().ok() } else { None };
+    for (code, state_name, fips) in STATES.iter() {
+        let is_fips = fips_query.is_some() && fips_query == fips.parse::<u32>().ok();
+        if query == code.to_lowercase() || query == normalize(state_name) || is_fips {
+            return Ok(code);
+        }
+    }
+
+    let matches: Vec<(&str, String)> = STATES.iter()
+        .map(|(code, state_name, _)| (*code, normalize(state_name)))
+        .filter(|(_, state_name)| state_name.starts_with(&query))
+        .collect();
+    if matches.len() == 1 && query.len() >= MIN_PREFIX {
+        let (code, state_name) = &matches[0];
+        // A whole word like "west" is more likely a region than the start of West Virginia, so
+        // only suggest the state
+        if state_name[query.len()..].starts_with(" ") {
+            return Err(format!("I don't know a region called {name}, did you mean {code}?", name=name, code=code));
+        }
+        return Ok(code);
+    }
+    if matches.len() > 1 && query.len() > 2 {
+        let codes: Vec<&str> = matches.iter().map(|(code, _)| *code).collect();
+        return Err(format!("{name} could be any of {codes}, which did you mean?", name=name, codes=codes.join(", ")));
+    }
+
+    match suggest(&query) {
+        Some(code) => return Err(format!("I don't know a state called {name}, did you mean {code}?", name=name, code=code)),
+        None => return Err(format!("I don't know a state called {name}", name=name)),
+    }
+}
+
+// The state whose code or name is fewest edits away from query, when it's close enough to be a typo
+fn suggest(query: &str) -> Option<&'static str> {
+    let mut best: Option<(usize, &'static str)> = None;
+    for (code, state_name, _) in STATES.iter() {
+        // A one-letter slip in a two-letter code matches half the country, so codes only count
+        // for extra or swapped letters
+        let code_distance = if query.len() > 2 { edit_distance(query, &code.to_lowercase()) } else { usize::max_value() };
+        let name_distance = edit_distance(query, &normalize(state_name));
+        let distance = code_distance.min(name_distance);
+        let is_better = match best {
+            Some((best_distance, _)) => distance < best_distance,
+            None => true,
+        };
+        if is_better {
+            best = Some((distance, code));
+        }
+    }
+    match best {
+        // Roughly one typo per four letters
+        Some((distance, code)) if distance <= 1.max(query.len() / 4) => Some(code),
+        _ => None,
+    }
+}
+
+// Levenshtein distance, counting two swapped letters as a single edit
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in 0..=a.len() {
+        d[i][0] = i;
+    }
+    for j in 0..=b.len() {
+        d[0][j] = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = (d[i - 1][j - 1] + cost).min(d[i - 1][j] + 1).min(d[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    return d[a.len()][b.len()];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_names_and_fips() {
+        assert_eq!(resolve("CA"), Ok("CA"));
+        assert_eq!(resolve("ca"), Ok("CA"));
+        assert_eq!(resolve("California"), Ok("CA"));
+        assert_eq!(resolve("new_york"), Ok("NY"));
+        assert_eq!(resolve("District of Columbia"), Ok("DC"));
+        assert_eq!(resolve("06"), Ok("CA"));
+        assert_eq!(resolve("6"), Ok("CA"));
+    }
+
+    #[test]
+    fn punctuation_and_prefixes() {
+        assert_eq!(resolve("Calif."), Ok("CA"));
+        assert_eq!(resolve("N.Y."), Ok("NY"));
+        assert_eq!(resolve("Mass"), Ok("MA"));
+        assert_eq!(resolve("west-virginia"), Ok("WV"));
+        // Too short to be sure of
+        assert!(resolve("Cal").is_err());
+    }
+
+    #[test]
+    fn ambiguous_prefixes() {
+        assert_eq!(resolve("north"), Err("north could be any of NC, ND, MP, which did you mean?".to_string()));
+        assert_eq!(resolve("new"), Err("new could be any of NH, NJ, NM, NY, which did you mean?".to_string()));
+    }
+
+    #[test]
+    fn whole_words_are_not_prefixes() {
+        // Even when a config has no "west" group
+        assert_eq!(resolve("west"), Err("I don't know a region called west, did you mean WV?".to_string()));
+        assert_eq!(resolve("Rhode"), Err("I don't know a region called Rhode, did you mean RI?".to_string()));
+        assert_eq!(resolve("Rhode Island"), Ok("RI"));
+    }
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(resolve("Califronia"), Err("I don't know a state called Califronia, did you mean CA?".to_string()));
+        assert_eq!(resolve("Texsa"), Err("I don't know a state called Texsa, did you mean TX?".to_string()));
+        assert_eq!(resolve("Atlantis"), Err("I don't know a state called Atlantis".to_string()));
+        assert_eq!(resolve("ZZ"), Err("I don't know a state called ZZ".to_string()));
+        assert!(resolve(" .. ").is_err());
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("texas", "texas"), 0);
+        assert_eq!(edit_distance("texsa", "texas"), 1);
+        assert_eq!(edit_distance("kansas", "arkansas"), 2);
+        assert_eq!(edit_distance("", "ohio"), 4);
+    }
+}
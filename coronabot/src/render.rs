@@ -0,0 +1,99 @@
+use crate::chart::{Chart, ChartSpec};
+use crate::config::WorkersConfig;
+use crate::gnuplot_renderer::GnuplotRenderer;
+use crate::plotters_renderer::PlottersRenderer;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Svg,
+    Pdf,
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Svg => "svg",
+            ImageFormat::Pdf => "pdf",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Svg => "image/svg+xml",
+            ImageFormat::Pdf => "application/pdf",
+        }
+    }
+
+    pub fn parse(value: &str) -> Result<ImageFormat, String> {
+        match value.to_lowercase().as_str() {
+            "png" => Ok(ImageFormat::Png),
+            "svg" => Ok(ImageFormat::Svg),
+            "pdf" => Ok(ImageFormat::Pdf),
+            _ => Err(format!("Unknown format {:}, use png, svg or pdf", value)),
+        }
+    }
+}
+
+// A chart ready to upload
+pub struct RenderedChart {
+    pub title: String,
+    pub alt_text: String,
+    pub format: ImageFormat,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum RenderError {
+    // The renderer can't run here at all, e.g. no gnuplot binary
+    Unavailable(String),
+    // The renderer can't produce the requested format
+    Unsupported(String),
+    Failed(String),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderError::Unavailable(msg) => write!(f, "chart renderer unavailable: {}", msg),
+            RenderError::Unsupported(msg) => write!(f, "unsupported chart format: {}", msg),
+            RenderError::Failed(msg) => write!(f, "failed to render chart: {}", msg),
+        }
+    }
+}
+
+// Turns a chart into image bytes
+pub trait ChartRenderer: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn render(&self, chart: &Chart, spec: &ChartSpec) -> Result<Vec<u8>, RenderError>;
+}
+
+// Picks the renderer named in the config. "auto" uses gnuplot when the binary is installed and
+// falls back to the in-process renderer otherwise.
+pub fn from_config(name: &str, workers: &WorkersConfig) -> Box<dyn ChartRenderer> {
+    let gnuplot = || GnuplotRenderer::new(workers.gnuplot_processes, Duration::from_secs(workers.timeout_secs));
+    let renderer: Box<dyn ChartRenderer> = match name {
+        "gnuplot" => Box::new(gnuplot()),
+        "plotters" => match PlottersRenderer::new() {
+            Ok(renderer) => Box::new(renderer),
+            Err(err) => panic!("Couldn't start the plotters renderer: {:}", err),
+        },
+        "auto" => {
+            if GnuplotRenderer::is_installed() {
+                Box::new(gnuplot())
+            } else {
+                match PlottersRenderer::new() {
+                    Ok(renderer) => Box::new(renderer),
+                    Err(err) => panic!("gnuplot isn't installed and the plotters fallback failed: {:}", err),
+                }
+            }
+        },
+        _ => panic!("Unknown renderer {:}, use auto, gnuplot or plotters", name),
+    };
+    println!("Rendering charts with {:}", renderer.name());
+    return renderer;
+}
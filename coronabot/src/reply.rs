@@ -0,0 +1,226 @@
+use crate::blocks::Message;
+use crate::slack_web::SlackWeb;
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+
+const PLACEHOLDER_TEXT: &str = "Working on it…";
+
+struct ReplyState {
+    placeholder_ts: Option<String>,
+    // The message holding the answer, once there is one, so it can be updated if the question is
+    // edited
+    answer_ts: Option<String>,
+    // Set once the command has been answered for good, e.g. after timing out, so a late result
+    // doesn't show up as well
+    closed: bool,
+}
+
+#[derive(Clone)]
+enum Destination {
+    // A thread in the channel rooted at thread_ts, or the channel itself
+    Thread(Option<String>),
+    // A slash command's response URL, which can answer just the person who asked
+    ResponseUrl { url: String, in_channel: bool },
+    // An interaction's response URL, to replace the message that was interacted with
+    Replace(String),
+}
+
+// Answers a single command, in the thread of the message that asked for it (or in the conversation,
+// for DMs) or to the slash command that did. Slow commands can post a placeholder first, which the
+// answer then replaces. Clones share the same placeholder.
+#[derive(Clone)]
+pub struct Reply {
+    web: SlackWeb,
+    channel: String,
+    destination: Destination,
+    state: Arc<Mutex<ReplyState>>,
+}
+
+impl Reply {
+    // thread_ts is the message being answered, or the root of the thread it's in. Without one the
+    // answer goes straight in the channel.
+    pub fn new(web: &SlackWeb, channel: &str, thread_ts: Option<&str>) -> Reply {
+        return Reply::with_destination(web, channel, Destination::Thread(thread_ts.map(|ts| ts.to_string())));
+    }
+
+    // Answers via a slash command's response_url, visible to everyone in the channel when in_channel
+    // is set and only to whoever ran the command otherwise
+    pub fn for_response_url(web: &SlackWeb, channel: &str, url: &str, in_channel: bool) -> Reply {
+        return Reply::with_destination(web, channel, Destination::ResponseUrl{url: url.to_string(), in_channel: in_channel});
+    }
+
+    // Replaces the message a button or menu was used on
+    pub fn replacing(web: &SlackWeb, channel: &str, response_url: &str) -> Reply {
+        return Reply::with_destination(web, channel, Destination::Replace(response_url.to_string()));
+    }
+
+    fn with_destination(web: &SlackWeb, channel: &str, destination: Destination) -> Reply {
+        let state = ReplyState{placeholder_ts: None, answer_ts: None, closed: false};
+        return Reply{web: web.clone(), channel: channel.to_string(), destination: destination, state: Arc::new(Mutex::new(state))};
+    }
+
+    pub fn channel(&self) -> &str {
+        return &self.channel;
+    }
+
+    pub fn thread_ts(&self) -> Option<&str> {
+        match &self.destination {
+            Destination::Thread(thread_ts) => thread_ts.as_deref(),
+            _ => None,
+        }
+    }
+
+    // Whether the answer goes in the conversation itself, rather than to a response URL that can
+    // answer privately or replace a message
+    pub fn in_conversation(&self) -> bool {
+        match self.destination {
+            Destination::Thread(_) => true,
+            _ => false,
+        }
+    }
+
+    // A new reply to the same question that replaces this one's answer rather than adding another,
+    // for when the question is edited
+    pub fn for_edit(&self) -> Reply {
+        let answer_ts = self.state.lock().unwrap().answer_ts.clone();
+        let reply = Reply::with_destination(&self.web, &self.channel, self.destination.clone());
+        // The old answer stands in for the placeholder, so the next thing sent replaces it
+        reply.state.lock().unwrap().placeholder_ts = answer_ts;
+        return reply;
+    }
+
+    pub fn is_closed(&self) -> bool {
+        return self.state.lock().unwrap().closed;
+    }
+
+    // Lets whoever asked know something's happening, for commands that take a while. Slash
+    // commands and interactions have already been acknowledged, so don't need it.
+    pub fn working(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed || state.placeholder_ts.is_some() {
+            return;
+        }
+        match self.destination {
+            Destination::Thread(_) => {},
+            _ => return,
+        }
+        match self.post(&Message::new(PLACEHOLDER_TEXT)) {
+            Ok(ts) => state.placeholder_ts = Some(ts),
+            Err(err) => println!("Failed to post placeholder: {:}", err),
+        }
+    }
+
+    // Sends text in the thread, replacing the placeholder if there is one
+    pub fn send(&self, text: &str) {
+        self.send_message(&Message::new(text));
+    }
+
+    // Like send, for messages made of blocks
+    pub fn send_message(&self, message: &Message) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            println!("Dropping reply to closed command in {:}: {:}", self.channel, message.text());
+            return;
+        }
+        let mut res = self.deliver(&mut state, message);
+        // Slack turns down the whole message over one block it doesn't like, e.g. an image it
+        // can't fetch. The text has everything important in it, so send that instead.
+        match &res {
+            Err(err) if err.contains("invalid_blocks") && !message.blocks().is_empty() => {
+                println!("Slack rejected the blocks in {:}, sending the text instead: {:}", self.channel, err);
+                res = self.deliver(&mut state, &Message::new(message.text()));
+            },
+            _ => {}
+        }
+        match res {
+            Ok(()) => {},
+            Err(err) => println!("Failed to reply in {:}: {:}", self.channel, err),
+        }
+    }
+
+    fn deliver(&self, state: &mut ReplyState, message: &Message) -> Result<(), String> {
+        match (&self.destination, state.placeholder_ts.clone()) {
+            (Destination::ResponseUrl{url, in_channel}, _) => {
+                let response_type = if *in_channel { "in_channel" } else { "ephemeral" };
+                return self.web.respond(url, &response_body(message, serde_json::json!({"response_type": response_type})));
+            },
+            (Destination::Replace(url), _) => {
+                return self.web.respond(url, &response_body(message, serde_json::json!({"replace_original": true})));
+            },
+            (Destination::Thread(_), Some(ts)) => {
+                let blocks = blocks_param(message);
+                let mut params = vec![("channel", self.channel.as_str()), ("ts", ts.as_str()), ("text", message.text())];
+                match &blocks {
+                    Some(blocks) => params.push(("blocks", blocks)),
+                    None => {}
+                }
+                self.web.call("chat.update", &params)?;
+                state.placeholder_ts = None;
+                state.answer_ts = Some(ts);
+                return Ok(());
+            },
+            (Destination::Thread(_), None) => {
+                let ts = self.post(message)?;
+                state.answer_ts = Some(ts);
+                return Ok(());
+            },
+        }
+    }
+
+    // Sends text as the final answer, anything sent afterwards is dropped
+    pub fn close_with(&self, text: &str) {
+        self.send(text);
+        self.state.lock().unwrap().closed = true;
+    }
+
+    // Removes the placeholder when the answer went somewhere else, e.g. a file shared in the thread
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.answer_ts = None;
+        match state.placeholder_ts.take() {
+            Some(ts) => {
+                match self.web.call("chat.delete", &[("channel", &self.channel), ("ts", &ts)]) {
+                    Ok(_) => {},
+                    Err(err) => println!("Failed to remove placeholder: {:}", err),
+                }
+            },
+            None => {}
+        }
+    }
+
+    fn post(&self, message: &Message) -> Result<String, String> {
+        let blocks = blocks_param(message);
+        let mut params = vec![("channel", self.channel.as_str()), ("text", message.text())];
+        match self.thread_ts() {
+            Some(thread_ts) => params.push(("thread_ts", thread_ts)),
+            None => {}
+        }
+        match &blocks {
+            Some(blocks) => params.push(("blocks", blocks)),
+            None => {}
+        }
+        let res = self.web.call("chat.postMessage", &params)?;
+        match res["ts"].as_str() {
+            Some(ts) => return Ok(ts.to_string()),
+            None => return Err("Slack didn't return the message's ts".to_string()),
+        }
+    }
+}
+
+// Web API methods take blocks as a JSON encoded parameter, and reject an empty list
+fn blocks_param(message: &Message) -> Option<String> {
+    if message.blocks().is_empty() {
+        return None;
+    }
+    return Some(serde_json::to_string(message.blocks()).unwrap());
+}
+
+// A response_url message: body with the message's text and blocks added
+fn response_body(message: &Message, body: Value) -> Value {
+    let mut body = body;
+    body["text"] = Value::from(message.text());
+    if !message.blocks().is_empty() {
+        body["blocks"] = Value::from(message.blocks().clone());
+    }
+    return body;
+}
@@ -0,0 +1,88 @@
+use crate::dispatch::{Dispatcher, IncomingMessage};
+use slack::{Event, RtmClient, Message};
+use std::sync::Arc;
+
+// Receives events over the (legacy) RTM connection
+pub struct RtmHandler {
+    dispatcher: Arc<Dispatcher>,
+}
+
+impl RtmHandler {
+    pub fn new(dispatcher: Arc<Dispatcher>) -> RtmHandler {
+        return RtmHandler{dispatcher: dispatcher};
+    }
+}
+
+// RTM doesn't say what kind of conversation it is, but DM ids start with D
+fn is_direct(channel: &str) -> bool {
+    return channel.starts_with("D");
+}
+
+#[allow(unused_variables)]
+impl slack::EventHandler for RtmHandler {
+    fn on_event(&mut self, cli: &RtmClient, event: Event) {
+        let incoming = match event {
+            Event::Message(msg) => {
+                match *msg {
+                    Message::Standard(msg) => {
+                        println!("msg: {:?}", msg);
+                        match (msg.channel, msg.text, msg.ts) {
+                            (Some(channel), Some(text), Some(ts)) => Some(IncomingMessage{
+                                is_direct: is_direct(&channel),
+                                channel: channel,
+                                text: text,
+                                ts: ts,
+                                thread_ts: msg.thread_ts,
+                                user: msg.user,
+                                bot_id: msg.bot_id,
+                                edited: false,
+                            }),
+                            // e.g. a message that's only attachments
+                            _ => None,
+                        }
+                    },
+                    Message::MessageChanged(msg) => {
+                        println!("msg changed: {:?}", msg);
+                        match (msg.channel, msg.message) {
+                            (Some(channel), Some(message)) => {
+                                // Also sent when e.g. a thread reply or link preview is added, which
+                                // isn't an edit
+                                let previous_text = msg.previous_message.and_then(|previous| previous.text);
+                                match (message.text, message.ts) {
+                                    (Some(text), Some(ts)) if Some(&text) != previous_text.as_ref() => Some(IncomingMessage{
+                                        is_direct: is_direct(&channel),
+                                        channel: channel,
+                                        text: text,
+                                        ts: ts,
+                                        // Answering a reply starts a thread on it, which Slack
+                                        // puts in the original thread anyway
+                                        thread_ts: None,
+                                        user: message.user,
+                                        bot_id: message.bot_id,
+                                        edited: true,
+                                    }),
+                                    _ => None,
+                                }
+                            },
+                            _ => None,
+                        }
+                    },
+                    // Joins, topic changes, file shares, deletions... none of them are commands
+                    _ => None,
+                }
+            },
+            _ => None,
+        };
+        match incoming {
+            Some(incoming) => self.dispatcher.dispatch(incoming),
+            None => {}
+        }
+    }
+    fn on_close(&mut self, cli: &RtmClient) {
+        println!("Connection closed");
+    }
+
+    fn on_connect(&mut self, cli: &RtmClient) {
+        println!("Coronabot connected");
+    }
+}
@@ -0,0 +1,208 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone};
+use chrono_tz::Tz;
+
+// Far enough ahead to find any valid schedule, including ones that only match on February 29th
+const MAX_DAYS_AHEAD: i64 = 366 * 8;
+
+// A cron-style schedule: "minute hour day-of-month month day-of-week", each field a *, number,
+// range (1-5), list (1,15) or step (*/15). Days of the week run from 0 (Sunday) to 6, 7 is also
+// Sunday. As in cron, when both day fields are restricted a day matching either one counts.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days_of_month: Vec<bool>,
+    months: Vec<bool>,
+    days_of_week: Vec<bool>,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl Schedule {
+    pub fn parse(expression: &str) -> Result<Schedule, String> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("Schedule {:?} should have 5 fields: minute hour day-of-month month day-of-week", expression));
+        }
+        let mut days_of_week = parse_field(fields[4], 0, 7)?;
+        // 7 is another way of writing Sunday
+        if days_of_week[7] {
+            days_of_week[0] = true;
+        }
+        return Ok(Schedule{
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days_of_month: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            days_of_week: days_of_week,
+            any_day_of_month: fields[2] == "*",
+            any_day_of_week: fields[4] == "*",
+        });
+    }
+
+    // The first time strictly after after that the schedule matches, in after's timezone
+    pub fn next_after(&self, after: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let tz = after.timezone();
+        let start = after.naive_local();
+        for day_offset in 0..MAX_DAYS_AHEAD {
+            let date = start.date() + Duration::days(day_offset);
+            if !self.matches_day(date) {
+                continue;
+            }
+            for hour in 0..24 {
+                if !self.hours[hour] {
+                    continue;
+                }
+                for minute in 0..60 {
+                    if !self.minutes[minute] {
+                        continue;
+                    }
+                    let time = date.and_hms(hour as u32, minute as u32, 0);
+                    if time <= start {
+                        continue;
+                    }
+                    // Times skipped by a DST change don't happen that day
+                    match tz.from_local_datetime(&time).earliest() {
+                        Some(time) => return Some(time),
+                        None => {}
+                    }
+                }
+            }
+        }
+        return None;
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        if !self.months[date.month() as usize] {
+            return false;
+        }
+        let day_of_month = self.days_of_month[date.day() as usize];
+        let day_of_week = self.days_of_week[date.weekday().num_days_from_sunday() as usize];
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (true, true) => true,
+            (true, false) => day_of_week,
+            (false, true) => day_of_month,
+            (false, false) => day_of_month || day_of_week,
+        }
+    }
+}
+
+// Which values from min to max a field allows, indexed by value
+fn parse_field(field: &str, min: usize, max: usize) -> Result<Vec<bool>, String> {
+    let mut allowed = vec![false; max + 1];
+    for part in field.split(",") {
+        let (range, step) = match part.find("/") {
+            Some(slash) => {
+                let step = part[slash+1..].parse::<usize>().map_err(|_| format!("Bad step in {:?}", part))?;
+                if step == 0 {
+                    return Err(format!("Bad step in {:?}", part));
+                }
+                (&part[..slash], step)
+            },
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else {
+            match range.find("-") {
+                Some(dash) => (parse_value(&range[..dash], min, max)?, parse_value(&range[dash+1..], min, max)?),
+                None => {
+                    let value = parse_value(range, min, max)?;
+                    // "5/15" means every 15 from 5
+                    if step > 1 { (value, max) } else { (value, value) }
+                }
+            }
+        };
+        if start > end {
+            return Err(format!("Range {:?} ends before it starts", range));
+        }
+        for value in (start..=end).step_by(step) {
+            allowed[value] = true;
+        }
+    }
+    return Ok(allowed);
+}
+
+fn parse_value(value: &str, min: usize, max: usize) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(value) if value >= min && value <= max => Ok(value),
+        _ => Err(format!("{:?} isn't a number from {:} to {:}", value, min, max)),
+    }
+}
+
+pub fn parse_timezone(name: &str) -> Result<Tz, String> {
+    return name.parse::<Tz>().map_err(|_| format!("Unknown timezone {:?}, use a name like America/New_York", name));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::America::New_York;
+    use chrono_tz::UTC;
+
+    fn next(expression: &str, after: DateTime<Tz>) -> DateTime<Tz> {
+        return Schedule::parse(expression).unwrap().next_after(&after).unwrap();
+    }
+
+    #[test]
+    fn daily() {
+        let after = UTC.ymd(2020, 6, 1).and_hms(8, 0, 0);
+        assert_eq!(next("0 9 * * *", after), UTC.ymd(2020, 6, 1).and_hms(9, 0, 0));
+        // Strictly after, so the time itself moves on to the next day
+        assert_eq!(next("0 9 * * *", UTC.ymd(2020, 6, 1).and_hms(9, 0, 0)), UTC.ymd(2020, 6, 2).and_hms(9, 0, 0));
+    }
+
+    #[test]
+    fn days_of_week() {
+        // June 6th 2020 was a Saturday
+        let saturday = UTC.ymd(2020, 6, 6).and_hms(12, 0, 0);
+        assert_eq!(next("30 8 * * 1-5", saturday), UTC.ymd(2020, 6, 8).and_hms(8, 30, 0));
+        assert_eq!(next("0 0 * * 7", saturday), UTC.ymd(2020, 6, 7).and_hms(0, 0, 0));
+        assert_eq!(next("0 0 * * 0,6", saturday), UTC.ymd(2020, 6, 7).and_hms(0, 0, 0));
+    }
+
+    #[test]
+    fn steps_and_lists() {
+        let after = UTC.ymd(2020, 6, 1).and_hms(10, 16, 0);
+        assert_eq!(next("*/15 * * * *", after), UTC.ymd(2020, 6, 1).and_hms(10, 30, 0));
+        assert_eq!(next("5/20 * * * *", after), UTC.ymd(2020, 6, 1).and_hms(10, 25, 0));
+        assert_eq!(next("0 9 1,15 * *", after), UTC.ymd(2020, 6, 15).and_hms(9, 0, 0));
+    }
+
+    #[test]
+    fn either_day_field() {
+        // The 15th or any Monday, whichever comes first. June 1st 2020 was a Monday.
+        let after = UTC.ymd(2020, 6, 2).and_hms(0, 0, 0);
+        assert_eq!(next("0 0 15 * 1", after), UTC.ymd(2020, 6, 8).and_hms(0, 0, 0));
+        assert_eq!(next("0 0 10 * 1", after), UTC.ymd(2020, 6, 8).and_hms(0, 0, 0));
+        assert_eq!(next("0 0 3 * 1", after), UTC.ymd(2020, 6, 3).and_hms(0, 0, 0));
+    }
+
+    #[test]
+    fn leap_day() {
+        let after = UTC.ymd(2021, 1, 1).and_hms(0, 0, 0);
+        assert_eq!(next("0 0 29 2 *", after), UTC.ymd(2024, 2, 29).and_hms(0, 0, 0));
+        assert_eq!(Schedule::parse("0 0 31 2 *").unwrap().next_after(&after), None);
+    }
+
+    #[test]
+    fn skipped_by_dst() {
+        // 2:30am didn't happen in New York on March 8th 2020
+        let after = New_York.ymd(2020, 3, 7).and_hms(12, 0, 0);
+        assert_eq!(next("30 2 * * *", after), New_York.ymd(2020, 3, 9).and_hms(2, 30, 0));
+    }
+
+    #[test]
+    fn bad_schedules() {
+        for expression in ["", "0 9 * *", "0 9 * * * *", "60 9 * * *", "0 24 * * *", "0 9 0 * *", "0 9 * 13 *", "0 9 * * 8",
+                           "*/0 * * * *", "5-1 * * * *", "a * * * *", "1-x * * * *"].iter() {
+            assert!(Schedule::parse(expression).is_err(), "{:}", expression);
+        }
+    }
+
+    #[test]
+    fn timezones() {
+        assert_eq!(parse_timezone("America/New_York"), Ok(New_York));
+        assert!(parse_timezone("Mars/Olympus_Mons").is_err());
+    }
+}
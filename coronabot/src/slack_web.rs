@@ -0,0 +1,124 @@
+use reqwest::blocking::Client;
+use serde_json::Value;
+
+const SLACK_API_URL: &str = "https://slack.com/api/";
+
+// Who the token belongs to
+#[derive(Debug, Clone)]
+pub struct Identity {
+    // The bot's user id, as it appears in mentions
+    pub user_id: String,
+}
+
+// Just enough of the Slack Web API for the things the RTM connection can't do
+#[derive(Clone)]
+pub struct SlackWeb {
+    token: String,
+    client: Client,
+}
+
+impl SlackWeb {
+    pub fn new(token: &str) -> SlackWeb {
+        return SlackWeb{token: token.to_string(), client: Client::new()};
+    }
+
+    // Calls a form-encoded Web API method, turning Slack's {"ok": false} responses into errors
+    pub fn call(&self, method: &str, params: &[(&str, &str)]) -> Result<Value, String> {
+        let mut url = SLACK_API_URL.to_string();
+        url.push_str(method);
+        let res = self.client.post(&url)
+            .bearer_auth(&self.token)
+            .form(params)
+            .send()
+            .and_then(|res| res.json::<Value>());
+        return check_response(method, res);
+    }
+
+    // Looks up the bot's own id, so it doesn't have to be configured
+    pub fn identity(&self) -> Result<Identity, String> {
+        let res = self.call("auth.test", &[])?;
+        let user_id = res["user_id"].as_str().ok_or("Slack didn't return a user id")?;
+        return Ok(Identity{user_id: user_id.to_string()});
+    }
+
+    // Calls a Web API method that takes a JSON body
+    pub fn call_json(&self, method: &str, body: &Value) -> Result<Value, String> {
+        let mut url = SLACK_API_URL.to_string();
+        url.push_str(method);
+        let res = self.client.post(&url)
+            .bearer_auth(&self.token)
+            .json(body)
+            .send()
+            .and_then(|res| res.json::<Value>());
+        return check_response(method, res);
+    }
+
+    // Posts a message to a response_url from a slash command or interaction. These don't need the
+    // token and answer with a plain "ok" rather than JSON.
+    pub fn respond(&self, response_url: &str, body: &Value) -> Result<(), String> {
+        let res = self.client.post(response_url).json(body).send();
+        match res {
+            Ok(res) if res.status().is_success() => return Ok(()),
+            Ok(res) => {
+                let status = res.status();
+                return Err(format!("Responding to Slack failed with status {:}: {:}", status, res.text().unwrap_or_default()));
+            },
+            Err(err) => return Err(format!("Responding to Slack failed: {:}", err)),
+        }
+    }
+
+    // Uploads a file and shares it in channel (in thread_ts's thread, if given) as a single message.
+    // Uses the external upload flow (get an upload URL, send the bytes, complete) since that's the
+    // only one that takes alt text.
+    pub fn upload_file(&self,
+                       channel: &str,
+                       thread_ts: Option<&str>,
+                       filename: &str,
+                       bytes: &[u8],
+                       title: &str,
+                       alt_text: &str) -> Result<(), String> {
+        let length = bytes.len().to_string();
+        let upload = self.call("files.getUploadURLExternal", &[
+            ("filename", filename),
+            ("length", &length),
+            ("alt_txt", alt_text),
+        ])?;
+        let upload_url = upload["upload_url"].as_str().ok_or("Slack didn't return an upload URL")?;
+        let file_id = upload["file_id"].as_str().ok_or("Slack didn't return a file id")?;
+
+        let res = self.client.post(upload_url)
+            .body(bytes.to_vec())
+            .send();
+        match res {
+            Ok(res) if res.status().is_success() => {},
+            Ok(res) => return Err(format!("Uploading {:} to Slack failed with status {:}", filename, res.status())),
+            Err(err) => return Err(format!("Uploading {:} to Slack failed: {:}", filename, err)),
+        }
+
+        let mut complete = serde_json::json!({
+            "files": [{"id": file_id, "title": title}],
+            "channel_id": channel,
+        });
+        match thread_ts {
+            Some(thread_ts) => complete["thread_ts"] = Value::from(thread_ts),
+            None => {}
+        }
+        self.call_json("files.completeUploadExternal", &complete)?;
+        return Ok(());
+    }
+}
+
+fn check_response(method: &str, res: reqwest::Result<Value>) -> Result<Value, String> {
+    match res {
+        Ok(body) => {
+            if body["ok"].as_bool().unwrap_or(false) {
+                return Ok(body);
+            }
+            let error = body["error"].as_str().unwrap_or("unknown error");
+            return Err(format!("Slack {:} failed: {:}", method, error));
+        },
+        Err(err) => {
+            return Err(format!("Slack {:} failed: {:}", method, err));
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use crate::dispatch::{Dispatcher, IncomingMessage, Interaction, SlashCommand};
+use crate::slack_web::SlackWeb;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tungstenite::Message;
+
+// How long to wait before reconnecting after the connection fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// Receives events over a Socket Mode websocket, for deployments that can't accept requests from
+// Slack. app_token is the app-level (xapp-) token with the connections:write scope. Never returns,
+// reconnecting whenever Slack closes the connection (which it does every few hours).
+pub fn run(app_token: &str, dispatcher: Arc<Dispatcher>) {
+    let web = SlackWeb::new(app_token);
+    loop {
+        match connect(&web, &dispatcher) {
+            Ok(()) => println!("Socket Mode connection closed, reconnecting"),
+            Err(err) => {
+                println!("Socket Mode connection failed: {:}", err);
+                thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+fn connect(web: &SlackWeb, dispatcher: &Dispatcher) -> Result<(), String> {
+    let res = web.call("apps.connections.open", &[])?;
+    let url = res["url"].as_str().ok_or("Slack didn't return a Socket Mode URL")?;
+    let (mut socket, _) = tungstenite::connect(url).map_err(|err| err.to_string())?;
+    println!("Connected to Slack over Socket Mode");
+
+    loop {
+        // Pings are answered by tungstenite itself
+        let text = match socket.read_message().map_err(|err| err.to_string())? {
+            Message::Text(text) => text,
+            Message::Close(_) => return Ok(()),
+            _ => continue,
+        };
+        let envelope: Value = match serde_json::from_str(&text) {
+            Ok(envelope) => envelope,
+            Err(err) => {
+                println!("Failed to parse Socket Mode message: {:}", err);
+                continue;
+            }
+        };
+
+        // Every envelope has to be acknowledged or Slack sends it again
+        match envelope["envelope_id"].as_str() {
+            Some(id) => {
+                let ack = serde_json::json!({"envelope_id": id}).to_string();
+                socket.write_message(Message::Text(ack)).map_err(|err| err.to_string())?;
+            },
+            None => {}
+        }
+
+        match envelope["type"].as_str() {
+            Some("hello") => {},
+            // Slack is about to close the connection, get a new one
+            Some("disconnect") => return Ok(()),
+            Some("events_api") => {
+                match IncomingMessage::from_event(&envelope["payload"]["event"]) {
+                    Some(msg) => dispatcher.dispatch(msg),
+                    None => {}
+                }
+            },
+            Some("slash_commands") => {
+                // The same fields as the HTTP form, as a JSON object
+                let fields: HashMap<String, String> = match envelope["payload"].as_object() {
+                    Some(payload) => payload.iter()
+                        .filter_map(|(k, v)| v.as_str().map(|v| (k.to_string(), v.to_string())))
+                        .collect(),
+                    None => HashMap::new(),
+                };
+                match SlashCommand::from_fields(&fields) {
+                    Some(cmd) => dispatcher.dispatch_command(cmd),
+                    None => println!("Ignoring malformed slash command: {:}", envelope["payload"]),
+                }
+            },
+            Some("interactive") => {
+                match Interaction::from_payload(&envelope["payload"]) {
+                    Some(interaction) => dispatcher.dispatch_interaction(interaction),
+                    None => {}
+                }
+            },
+            other => println!("Ignoring Socket Mode message of type {:?}", other),
+        }
+    }
+}
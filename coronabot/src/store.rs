@@ -0,0 +1,242 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use crate::alerts::Condition;
+use crate::chart::Theme;
+
+// Everything the bot remembers about a single channel (or DM)
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChannelState {
+    #[serde(default)]
+    pub definitions: HashMap<String, String>,
+
+    #[serde(default)]
+    pub theme: Option<Theme>,
+
+    #[serde(default)]
+    pub subscriptions: Vec<Subscription>,
+
+    #[serde(default)]
+    pub alerts: Vec<Alert>,
+}
+
+// A report sent to a channel (or DM) on a schedule, set up with subscribe
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Subscription {
+    pub id: String,
+    // The command to run, e.g. "CA positive"
+    pub query: String,
+    // Cron-style, see schedule::Schedule
+    pub schedule: String,
+    pub timezone: String,
+    // The schedule as it was asked for, e.g. "on weekdays at 9:00"
+    pub description: String,
+}
+
+// Posts to the channel when a region's metric meets a condition, set up with alert
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Alert {
+    pub id: String,
+    pub region: String,
+    // A variable or one of the channel's saved expressions
+    pub metric: String,
+    pub condition: Condition,
+    // Whether the condition held when last checked. Alerts only go off when it starts to, not on
+    // every refresh while it does.
+    #[serde(default)]
+    pub triggered: bool,
+}
+
+impl Alert {
+    pub fn describe(&self) -> String {
+        return format!("{region} {metric} {condition}", region=self.region, metric=self.metric, condition=self.condition.describe());
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct StoreData {
+    #[serde(default)]
+    channels: HashMap<String, ChannelState>,
+
+    // The day of data each briefing and subscription last went out with, so a restart doesn't send
+    // it again. Keyed by briefing, see briefings::Briefing.
+    #[serde(default)]
+    briefed: HashMap<String, NaiveDate>,
+}
+
+// Small JSON-backed store for state that has to survive restarts
+pub struct Store {
+    path: String,
+    data: StoreData,
+}
+
+impl Store {
+    pub fn load(path: &str) -> Store {
+        let data = match fs::read_to_string(path) {
+            Ok(body) => {
+                match serde_json::from_str(&body) {
+                    Ok(data) => data,
+                    Err(err) => {
+                        println!("Failed to parse store {:}, starting empty: {:}", path, err);
+                        StoreData::default()
+                    }
+                }
+            },
+            Err(_) => {
+                println!("No store found at {:}, starting empty", path);
+                StoreData::default()
+            }
+        };
+        return Store{path: path.to_string(), data: data};
+    }
+
+    // Write to a temp file and rename so a crash mid-write can't corrupt the store
+    fn save(&self) {
+        let body = serde_json::to_string_pretty(&self.data).unwrap();
+        let mut tmp_path = self.path.clone();
+        tmp_path.push_str(".tmp");
+        let res = fs::write(&tmp_path, body).and_then(|_| fs::rename(&tmp_path, &self.path));
+        match res {
+            Ok(()) => {},
+            Err(err) => println!("Failed to save store to {:}: {:}", self.path, err),
+        }
+    }
+
+    pub fn definitions(&self, channel: &str) -> HashMap<String, String> {
+        match self.data.channels.get(channel) {
+            Some(state) => state.definitions.clone(),
+            None => HashMap::new(),
+        }
+    }
+
+    pub fn define(&mut self, channel: &str, name: &str, expression: &str) {
+        let state = self.data.channels.entry(channel.to_string()).or_default();
+        state.definitions.insert(name.to_string(), expression.to_string());
+        self.save();
+    }
+
+    pub fn undefine(&mut self, channel: &str, name: &str) -> bool {
+        let removed = match self.data.channels.get_mut(channel) {
+            Some(state) => state.definitions.remove(name).is_some(),
+            None => false,
+        };
+        if removed {
+            self.save();
+        }
+        return removed;
+    }
+
+    pub fn theme(&self, channel: &str) -> Option<Theme> {
+        return self.data.channels.get(channel).and_then(|state| state.theme);
+    }
+
+    pub fn set_theme(&mut self, channel: &str, theme: Theme) {
+        let state = self.data.channels.entry(channel.to_string()).or_default();
+        state.theme = Some(theme);
+        self.save();
+    }
+
+    pub fn subscriptions(&self, channel: &str) -> Vec<Subscription> {
+        match self.data.channels.get(channel) {
+            Some(state) => state.subscriptions.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    // Every channel's subscriptions, for the scheduler
+    pub fn all_subscriptions(&self) -> Vec<(String, Subscription)> {
+        let mut all = Vec::new();
+        for (channel, state) in self.data.channels.iter() {
+            for subscription in state.subscriptions.iter() {
+                all.push((channel.clone(), subscription.clone()));
+            }
+        }
+        return all;
+    }
+
+    pub fn subscribe(&mut self, channel: &str, subscription: Subscription) {
+        let state = self.data.channels.entry(channel.to_string()).or_default();
+        state.subscriptions.push(subscription);
+        self.save();
+    }
+
+    // Removes the subscriptions with the given ids, returning how many there were
+    pub fn unsubscribe(&mut self, channel: &str, ids: &[String]) -> usize {
+        let removed = match self.data.channels.get_mut(channel) {
+            Some(state) => {
+                let before = state.subscriptions.len();
+                state.subscriptions.retain(|subscription| !ids.contains(&subscription.id));
+                before - state.subscriptions.len()
+            },
+            None => 0,
+        };
+        for id in ids.iter() {
+            self.data.briefed.remove(id);
+        }
+        if removed > 0 {
+            self.save();
+        }
+        return removed;
+    }
+
+    pub fn briefed(&self, key: &str) -> Option<NaiveDate> {
+        return self.data.briefed.get(key).cloned();
+    }
+
+    pub fn set_briefed(&mut self, key: &str, data_date: NaiveDate) {
+        self.data.briefed.insert(key.to_string(), data_date);
+        self.save();
+    }
+
+    pub fn alerts(&self, channel: &str) -> Vec<Alert> {
+        match self.data.channels.get(channel) {
+            Some(state) => state.alerts.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    // Every channel's alerts, to check against new data
+    pub fn all_alerts(&self) -> Vec<(String, Alert)> {
+        let mut all = Vec::new();
+        for (channel, state) in self.data.channels.iter() {
+            for alert in state.alerts.iter() {
+                all.push((channel.clone(), alert.clone()));
+            }
+        }
+        return all;
+    }
+
+    pub fn add_alert(&mut self, channel: &str, alert: Alert) {
+        let state = self.data.channels.entry(channel.to_string()).or_default();
+        state.alerts.push(alert);
+        self.save();
+    }
+
+    // Removes the alerts with the given ids, returning how many there were
+    pub fn remove_alerts(&mut self, channel: &str, ids: &[String]) -> usize {
+        let removed = match self.data.channels.get_mut(channel) {
+            Some(state) => {
+                let before = state.alerts.len();
+                state.alerts.retain(|alert| !ids.contains(&alert.id));
+                before - state.alerts.len()
+            },
+            None => 0,
+        };
+        if removed > 0 {
+            self.save();
+        }
+        return removed;
+    }
+
+    pub fn set_alert_triggered(&mut self, channel: &str, id: &str, triggered: bool) {
+        let alert = self.data.channels.get_mut(channel)
+            .and_then(|state| state.alerts.iter_mut().find(|alert| alert.id == id));
+        match alert {
+            Some(alert) => alert.triggered = triggered,
+            // Removed while it was being checked
+            None => return,
+        }
+        self.save();
+    }
+}
@@ -0,0 +1,211 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A unit of work for the pool. timed_out is called instead of waiting any longer when run takes
+// longer than the pool's timeout.
+pub struct Job {
+    pub name: String,
+    pub run: Box<dyn FnOnce() + Send>,
+    pub timed_out: Box<dyn FnOnce() + Send>,
+}
+
+// A fixed number of threads working through a bounded queue of jobs. Jobs that time out are left
+// running on their own threads, up to one per worker. Past that, workers wait for them to finish
+// before taking more jobs, so the queue fills up instead of threads piling up.
+pub struct WorkerPool {
+    sender: SyncSender<Job>,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize, queue_size: usize, timeout: Duration) -> WorkerPool {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let job_threads = Arc::new(Semaphore::new(workers.max(1) * 2));
+        for i in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            let job_threads = job_threads.clone();
+            thread::spawn(move || work(i, receiver, job_threads, timeout));
+        }
+        println!("Started {:} workers", workers.max(1));
+        return WorkerPool{sender: sender};
+    }
+
+    // Queues job, failing straight away rather than blocking when the queue is full
+    pub fn submit(&self, job: Job) -> Result<(), String> {
+        match self.sender.try_send(job) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Full(job)) => return Err(format!("Queue full, dropping {:}", job.name)),
+            Err(TrySendError::Disconnected(job)) => return Err(format!("No workers left, dropping {:}", job.name)),
+        }
+    }
+}
+
+fn work(id: usize, receiver: Arc<Mutex<Receiver<Job>>>, job_threads: Arc<Semaphore>, timeout: Duration) {
+    loop {
+        // A thread for the next job, before taking it off the queue so it doesn't sit waiting here
+        let permit = loop {
+            match job_threads.acquire(timeout) {
+                Some(permit) => break permit,
+                None => println!("Worker {:} waiting for abandoned jobs to finish", id),
+            }
+        };
+
+        // Only hold the lock while waiting for the next job, not while running it
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        println!("Worker {:} running {:}", id, job.name);
+
+        // Run on a thread of its own so a stuck job only costs that thread, not the worker. There's
+        // no way to stop it, but whatever it's waiting on (e.g. gnuplot) has a timeout of its own.
+        // The thread holds its permit until the job really finishes.
+        let (done, finished) = mpsc::channel();
+        let run = job.run;
+        thread::spawn(move || {
+            run();
+            drop(permit);
+            let _ = done.send(());
+        });
+        match finished.recv_timeout(timeout) {
+            Ok(()) => {},
+            Err(RecvTimeoutError::Timeout) => {
+                println!("Worker {:} gave up on {:} after {:?}", id, job.name, timeout);
+                (job.timed_out)();
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                println!("Worker {:}: {:} panicked", id, job.name);
+            }
+        }
+    }
+}
+
+// Limits how many of something can happen at once, e.g. gnuplot processes
+pub struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+// Gives the permit back when dropped
+pub struct Permit {
+    semaphore: Arc<Semaphore>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        return Semaphore{available: Mutex::new(permits.max(1)), released: Condvar::new()};
+    }
+
+    // Waits up to timeout for a permit, None if there still isn't one by then
+    pub fn acquire(self: &Arc<Semaphore>, timeout: Duration) -> Option<Permit> {
+        let deadline = Instant::now() + timeout;
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            available = self.released.wait_timeout(available, deadline - now).unwrap().0;
+        }
+        *available -= 1;
+        return Some(Permit{semaphore: self.clone()});
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.semaphore.available.lock().unwrap() += 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A job that signals when it starts, then blocks until its gate is released or dropped
+    fn blocking_job(name: &str, started: mpsc::Sender<String>, gate: Receiver<()>, timed_out: mpsc::Sender<String>) -> Job {
+        let run_name = name.to_string();
+        let timed_out_name = name.to_string();
+        return Job{
+            name: name.to_string(),
+            run: Box::new(move || {
+                let _ = started.send(run_name);
+                let _ = gate.recv();
+            }),
+            timed_out: Box::new(move || {
+                let _ = timed_out.send(timed_out_name);
+            }),
+        };
+    }
+
+    #[test]
+    fn gives_up_on_slow_jobs() {
+        let pool = WorkerPool::new(1, 1, Duration::from_millis(50));
+        let (started, started_rx) = mpsc::channel();
+        let (timed_out, timed_out_rx) = mpsc::channel();
+        let (release, gate) = mpsc::channel();
+        pool.submit(blocking_job("slow", started, gate, timed_out)).unwrap();
+        assert_eq!(started_rx.recv_timeout(Duration::from_secs(5)), Ok("slow".to_string()));
+        assert_eq!(timed_out_rx.recv_timeout(Duration::from_secs(5)), Ok("slow".to_string()));
+        drop(release);
+
+        // The worker moves on to the next job
+        let (done, done_rx) = mpsc::channel();
+        pool.submit(Job{name: "quick".to_string(), run: Box::new(move || done.send(()).unwrap()), timed_out: Box::new(|| {})}).unwrap();
+        assert_eq!(done_rx.recv_timeout(Duration::from_secs(5)), Ok(()));
+    }
+
+    #[test]
+    fn refuses_jobs_when_queue_is_full() {
+        let queue_size = 2;
+        let pool = WorkerPool::new(1, queue_size, Duration::from_secs(60));
+        let (started, started_rx) = mpsc::channel();
+        let (timed_out, _timed_out_rx) = mpsc::channel();
+        let mut releases = Vec::new();
+        let mut job = |name: &str| {
+            let (release, gate) = mpsc::channel();
+            releases.push(release);
+            return blocking_job(name, started.clone(), gate, timed_out.clone());
+        };
+
+        // The worker is busy with the first job, so the rest wait in the queue
+        pool.submit(job("running")).unwrap();
+        assert_eq!(started_rx.recv_timeout(Duration::from_secs(5)), Ok("running".to_string()));
+        for i in 0..queue_size {
+            pool.submit(job(&format!("queued {}", i))).unwrap();
+        }
+        let err = pool.submit(job("one too many")).unwrap_err();
+        assert!(err.contains("Queue full") && err.contains("one too many"), "{:}", err);
+
+        drop(releases);
+        for i in 0..queue_size {
+            assert_eq!(started_rx.recv_timeout(Duration::from_secs(5)), Ok(format!("queued {}", i)));
+        }
+    }
+
+    #[test]
+    fn semaphore_times_out() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let permit = semaphore.acquire(Duration::from_millis(10)).unwrap();
+        let start = Instant::now();
+        assert!(semaphore.acquire(Duration::from_millis(50)).is_none());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        drop(permit);
+        assert!(semaphore.acquire(Duration::from_millis(10)).is_some());
+    }
+
+    #[test]
+    fn semaphore_wakes_waiters() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let permit = semaphore.acquire(Duration::from_millis(10)).unwrap();
+        let holder = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(permit);
+        });
+        assert!(semaphore.acquire(Duration::from_secs(5)).is_some());
+        holder.join().unwrap();
+    }
+}